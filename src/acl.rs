@@ -0,0 +1,227 @@
+use command::Command;
+use nodespec::NodeSpec;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Something a client may be allowed to do with a node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Create,
+    Update,
+    Delete,
+}
+
+/// Which users may do what, per subtree. A rule applies to its node and everything below it,
+/// unless a rule for a deeper node says otherwise. Rules for a specific user take precedence
+/// over rules for everyone on the same node. Anything no rule grants is denied.
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// The user the rule applies to, or `None` for everyone
+    user: Option<String>,
+    nodespec: NodeSpec,
+    granted: Vec<Access>,
+}
+
+impl Access {
+    fn from_char(c: char) -> Option<Access> {
+        match c {
+            'r' => Some(Access::Read),
+            'c' => Some(Access::Create),
+            'u' => Some(Access::Update),
+            'd' => Some(Access::Delete),
+            _   => None,
+        }
+    }
+}
+
+impl Acl {
+    /// Reads rules from a file. Every line holds a user name (or `*` for everyone), a nodespec
+    /// and the letters of the granted permissions (`r`ead, `c`reate, `u`pdate, `d`elete, or `-`
+    /// for none), separated by spaces. Empty lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> io::Result<Acl> {
+        let mut acl = Acl::default();
+
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let rule = Rule::parse(line).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), i + 1, e))
+            })?;
+            acl.rules.push(rule);
+        }
+
+        Ok(acl)
+    }
+
+    /// Returns true if `user` may do `access` on the node at `nodespec`. Clients that haven't
+    /// authenticated only get what's granted to everyone.
+    pub fn allows(&self, user: Option<&str>, nodespec: &NodeSpec, access: Access) -> bool {
+        self.rules.iter()
+            .filter(|rule| rule.applies_to(user) && nodespec.starts_with(&rule.nodespec))
+            .max_by_key(|rule| (rule.nodespec.iter().count(), rule.user.is_some()))
+            .map_or(false, |rule| rule.granted.contains(&access))
+    }
+
+    /// Returns true if `user` may do `access` on the node at `nodespec` and everything below it.
+    pub fn allows_subtree(&self, user: Option<&str>, nodespec: &NodeSpec, access: Access) -> bool {
+        self.allows(user, nodespec, access) && self.rules.iter()
+            .filter(|rule| rule.applies_to(user) && rule.nodespec.starts_with(nodespec))
+            .all(|rule| self.allows(user, &rule.nodespec, access))
+    }
+
+    /// Returns true if `user` may execute `cmd`. Commands that work on a subtree, like dumps,
+    /// recursive deletes and patterns, need access to all of it.
+    pub fn permits(&self, user: Option<&str>, cmd: &Command) -> bool {
+        let root = NodeSpec::root();
+        match cmd {
+            Command::Create(nodespec, name, ..) => {
+                let mut nodespec = nodespec.clone();
+                nodespec.push(name.clone());
+                self.allows(user, &nodespec, Access::Create)
+            },
+            Command::Read(nodespec) | Command::ReadVersion(nodespec) |
+            Command::List(nodespec, _) | Command::Ttl(nodespec) => {
+                self.allows(user, nodespec, Access::Read)
+            },
+            Command::Dump(nodespec, _) | Command::Watch(nodespec) => {
+                self.allows_subtree(user, nodespec, Access::Read)
+            },
+            Command::Set(nodespec, ..) => {
+                self.allows(user, nodespec, Access::Create) && self.allows(user, nodespec, Access::Update)
+            },
+            Command::ReadMatching(pattern) => self.allows_subtree(user, &pattern.prefix(), Access::Read),
+            Command::UpdateMatching(pattern, _) => {
+                self.allows_subtree(user, &pattern.prefix(), Access::Update)
+            },
+            Command::Update(nodespec, ..) | Command::Cas(nodespec, ..) |
+            Command::Incr(nodespec, _) | Command::Decr(nodespec, _) |
+            Command::Append(nodespec, _) | Command::Prepend(nodespec, _) |
+            Command::Toggle(nodespec) | Command::Expire(nodespec, _) => {
+                self.allows(user, nodespec, Access::Update)
+            },
+            Command::Push(nodespec, ..) | Command::Insert(nodespec, ..) => {
+                self.allows(user, nodespec, Access::Create)
+            },
+            Command::Pop(nodespec, _)        => self.allows_subtree(user, nodespec, Access::Delete),
+            Command::Delete(nodespec, false) => self.allows(user, nodespec, Access::Delete),
+            Command::Delete(nodespec, true)  => self.allows_subtree(user, nodespec, Access::Delete),
+
+            // Snapshots hold, or replace, the whole store
+            Command::Save(_) => self.allows_subtree(user, &root, Access::Read),
+            Command::Load(_) => {
+                [ Access::Read, Access::Create, Access::Update, Access::Delete ].iter()
+                    .all(|&access| self.allows_subtree(user, &root, access))
+            },
+
+            Command::Unwatch(_) | Command::Auth(..) | Command::Proto(_) => true,
+            Command::Begin | Command::Commit | Command::Abort => true,
+        }
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Rule, &'static str> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (user, nodespec, granted) = match fields[..] {
+            [ user, nodespec, granted ] => (user, nodespec, granted),
+            _                           => return Err("expected `<user> <nodespec> <permissions>`"),
+        };
+
+        let granted = match granted {
+            "-" => Vec::new(),
+            _   => granted.chars().map(Access::from_char).collect::<Option<_>>()
+                       .ok_or("permissions must be letters out of `rcud`, or `-`")?,
+        };
+
+        Ok(Rule {
+            user: Some(user.to_string()).filter(|u| u != "*"),
+            nodespec: nodespec.parse()?,
+            granted,
+        })
+    }
+
+    fn applies_to(&self, user: Option<&str>) -> bool {
+        self.user.is_none() || self.user.as_ref().map(String::as_str) == user
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(lines: &[&str]) -> Acl {
+        Acl {
+            rules: lines.iter().map(|line| Rule::parse(line).unwrap()).collect(),
+        }
+    }
+
+    fn permits(acl: &Acl, user: &str, cmd: &str) -> bool {
+        acl.permits(Some(user), &cmd.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert!(Rule::parse("payments teams.payments").is_err());
+        assert!(Rule::parse("payments teams.payments rwx").is_err());
+        assert!(Rule::parse("payments teams..payments r").is_err());
+
+        let rule = Rule::parse("* . -").unwrap();
+        assert_eq!(rule.user, None);
+        assert!(rule.granted.is_empty());
+    }
+
+    #[test]
+    fn inherit_rules() {
+        let acl = acl(&[
+            "*        .               r",
+            "*        secrets         -",
+            "payments teams.payments  rcud",
+            "payments teams.payments.audit r",
+        ]);
+        let ns = |s: &str| s.parse::<NodeSpec>().unwrap();
+
+        assert!(acl.allows(None, &ns("global.region"), Access::Read));
+        assert!(!acl.allows(None, &ns("global.region"), Access::Update));
+        assert!(!acl.allows(Some("payments"), &ns("secrets.key"), Access::Read));
+        assert!(acl.allows(Some("payments"), &ns("teams.payments.limits"), Access::Update));
+        assert!(!acl.allows(Some("search"), &ns("teams.payments.limits"), Access::Update));
+
+        // Deeper rules take precedence
+        assert!(!acl.allows(Some("payments"), &ns("teams.payments.audit.last"), Access::Update));
+        assert!(acl.allows_subtree(Some("payments"), &ns("teams.payments.limits"), Access::Delete));
+        assert!(!acl.allows_subtree(Some("payments"), &ns("teams.payments"), Access::Delete));
+        assert!(!acl.allows_subtree(Some("payments"), &NodeSpec::root(), Access::Read));
+    }
+
+    #[test]
+    fn permit_commands() {
+        let acl = acl(&[
+            "payments teams.payments  rcud",
+            "payments global          r",
+        ]);
+
+        assert!(permits(&acl, "payments", "create teams.payments limits map"));
+        assert!(!permits(&acl, "payments", "create teams search map"));
+        assert!(permits(&acl, "payments", "update teams.payments.limits.daily 100"));
+        assert!(permits(&acl, "payments", "read global.region"));
+        assert!(permits(&acl, "payments", "read global.*.region"));
+        assert!(!permits(&acl, "payments", "read *.region"));
+        assert!(!permits(&acl, "payments", "update global.region :eu"));
+        assert!(!permits(&acl, "payments", "delete -r global"));
+        assert!(permits(&acl, "payments", "watch teams.payments"));
+        assert!(!permits(&acl, "payments", "save"));
+        assert!(permits(&acl, "payments", "begin"));
+        assert!(!permits(&acl, "search", "read global.region"));
+    }
+}
@@ -0,0 +1,330 @@
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str;
+use std::time::{Duration, Instant};
+
+/// Failed attempts for a user that are allowed before they're slowed down.
+const FREE_FAILURES: u32 = 3;
+
+/// The longest a user has to wait before trying again, in seconds.
+const MAX_DELAY: u64 = 60;
+
+const SALT_LEN: usize = 16;
+
+/// How many PBKDF2 iterations new secrets are hashed with. Tests use fewer, since they run
+/// unoptimized.
+#[cfg(not(test))]
+const ITERATIONS: u32 = 100_000;
+#[cfg(test)]
+const ITERATIONS: u32 = 10;
+
+/// A password or token. Its `Debug` output doesn't show it, so it doesn't end up in the
+/// server's output when commands are printed.
+#[derive(Clone, PartialEq)]
+pub struct Secret(pub String);
+
+/// The users that may connect, with the salted hashes of their secrets.
+pub struct Users {
+    users: HashMap<String, Hash>,
+    failures: HashMap<String, Failures>,
+    /// Quick to check hashes of secrets that were right, by user
+    remembered: HashMap<String, Vec<u8>>,
+}
+
+/// What a secret has to hash to, to authenticate as a user.
+pub struct Challenge(Hash);
+
+/// A secret hashed with PBKDF2.
+#[derive(Clone)]
+struct Hash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+struct Failures {
+    count: u32,
+    retry_at: Instant,
+}
+
+impl Users {
+    /// Reads users from a file. Every line holds a user name, a number of iterations, a salt
+    /// and the hash of the secret derived with PBKDF2-HMAC-SHA256, separated by spaces. The
+    /// salt and hash are written in hex, as printed by `hash_line`. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn load(path: &Path) -> io::Result<Users> {
+        let mut users = HashMap::new();
+
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let entry = match fields[..] {
+                [ user, iterations, salt, hash ] => {
+                    let iterations = iterations.parse().ok().filter(|&n| n > 0);
+                    match (iterations, from_hex(salt), from_hex(hash)) {
+                        (Some(iterations), Some(salt), Some(hash)) => Some((user, Hash { iterations, salt, hash })),
+                        _                                          => None,
+                    }
+                },
+                _ => None,
+            };
+            let (user, hash) = entry.ok_or_else(|| {
+                let msg = format!("{}:{}: expected `<user> <iterations> <salt> <hash>`", path.display(), i + 1);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+
+            users.insert(user.to_string(), hash);
+        }
+
+        Ok(Users {
+            users,
+            failures: HashMap::new(),
+            remembered: HashMap::new(),
+        })
+    }
+
+    /// Checks the secret of a user. After a few failed attempts, every next attempt for that
+    /// user has to wait longer, regardless of whether the secret is right.
+    pub fn authenticate(&mut self, user: &str, secret: &Secret, now: Instant) -> Result<(), &'static str> {
+        let valid = self.challenge(user, now)?.verify(secret);
+        self.finish(user, valid)
+    }
+
+    /// Starts an attempt to authenticate as `user`, which counts as failed until `finish` is
+    /// told otherwise, so attempts that are checked at the same time are limited as well.
+    /// Unknown users get a challenge that no secret passes, but that takes as long to check.
+    pub fn challenge(&mut self, user: &str, now: Instant) -> Result<Challenge, &'static str> {
+        if self.failures.get(user).map_or(false, |f| f.retry_at > now) {
+            return Err("too many failed attempts, try again later");
+        }
+
+        let hash = match self.users.get(user) {
+            Some(h) => h.clone(),
+            None    => return Ok(Challenge(Hash {
+                iterations: ITERATIONS,
+                salt: vec![ 0; SALT_LEN ],
+                hash: Vec::new(),
+            })),
+        };
+
+        // Only known users are tracked, so unknown names can't fill up the table
+        let failures = self.failures.entry(user.to_string()).or_insert(Failures {
+            count: 0,
+            retry_at: now,
+        });
+        failures.count += 1;
+        if failures.count >= FREE_FAILURES {
+            let delay = 1u64.checked_shl(failures.count - FREE_FAILURES).unwrap_or(MAX_DELAY);
+            failures.retry_at = now + Duration::from_secs(cmp::min(delay, MAX_DELAY));
+        }
+
+        Ok(Challenge(hash))
+    }
+
+    /// Finishes an attempt started with `challenge`.
+    pub fn finish(&mut self, user: &str, valid: bool) -> Result<(), &'static str> {
+        if !valid {
+            return Err("invalid user or secret");
+        }
+        self.failures.remove(user);
+        Ok(())
+    }
+
+    /// Remembers that `secret` is right for `user`, so `is_remembered` can check it without
+    /// the slow hash. Only the last secret is remembered for every user.
+    pub fn remember(&mut self, user: &str, secret: &Secret) {
+        if let Some(h) = self.users.get(user) {
+            self.remembered.insert(user.to_string(), hmac(&h.salt, &[ secret.0.as_bytes() ]));
+        }
+    }
+
+    pub fn is_remembered(&self, user: &str, secret: &Secret) -> bool {
+        match (self.users.get(user), self.remembered.get(user)) {
+            (Some(h), Some(hash)) => constant_time_eq(&hmac(&h.salt, &[ secret.0.as_bytes() ]), hash),
+            _                     => false,
+        }
+    }
+}
+
+impl Challenge {
+    /// Checks `secret`, which is slow on purpose. It doesn't need the users table, so it can be
+    /// done without holding any locks.
+    pub fn verify(&self, secret: &Secret) -> bool {
+        let Challenge(ref h) = *self;
+        constant_time_eq(&hash_secret(&h.salt, &secret.0, h.iterations), &h.hash)
+    }
+}
+
+/// Returns a line for the users file, with a new random salt.
+pub fn hash_line(user: &str, secret: &str) -> io::Result<String> {
+    let mut salt = [0; SALT_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut salt)?;
+    let hash = hash_secret(&salt, secret, ITERATIONS);
+    Ok(format!("{} {} {} {}", user, ITERATIONS, to_hex(&salt), to_hex(&hash)))
+}
+
+/// Derives a 32 byte key from the secret with PBKDF2-HMAC-SHA256 (RFC 8018).
+fn hash_secret(salt: &[u8], secret: &str, iterations: u32) -> Vec<u8> {
+    let key = secret.as_bytes();
+
+    // A single block is as long as the output of SHA-256
+    let mut u      = hmac(key, &[ salt, &[ 0, 0, 0, 1 ] ]);
+    let mut result = u.clone();
+    for _ in 1..iterations {
+        u = hmac(key, &[ &u ]);
+        for (r, x) in result.iter_mut().zip(&u) {
+            *r ^= x;
+        }
+    }
+    result
+}
+
+/// Returns the HMAC-SHA256 (RFC 2104) of the concatenated `parts`.
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        let mut hasher = Sha256::default();
+        hasher.input(key);
+        block[..32].copy_from_slice(&hasher.result());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let ipad: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    let opad: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+
+    let mut inner = Sha256::default();
+    inner.input(&ipad);
+    for part in parts {
+        inner.input(part);
+    }
+
+    let mut outer = Sha256::default();
+    outer.input(&opad);
+    outer.input(&inner.result());
+    outer.result().to_vec()
+}
+
+/// Compares without stopping at the first difference, so the time taken doesn't tell how
+/// much of a hash was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    s.as_bytes().chunks(2)
+        .map(|pair| u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    fn users(lines: &[String]) -> Users {
+        let path = env::temp_dir().join(format!("um-test-{}.users", ::std::process::id()));
+        File::create(&path).unwrap().write_all(lines.join("\n").as_bytes()).unwrap();
+        let users = Users::load(&path);
+        fs::remove_file(&path).unwrap();
+        users.unwrap()
+    }
+
+    #[test]
+    fn derive_keys() {
+        // From RFC 7914, section 11
+        assert_eq!(to_hex(&hash_secret(b"salt", "passwd", 1)),
+                   "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+        assert!(hash_secret(b"salt", "passwd", 2) != hash_secret(b"salt", "passwd", 1));
+    }
+
+    #[test]
+    fn check_secrets() {
+        let mut users = users(&[
+            "# deploy tooling".to_string(),
+            hash_line("deploy", "hunter2").unwrap(),
+            hash_line("backup", "correct horse").unwrap(),
+        ]);
+        let now = Instant::now();
+
+        assert!(users.authenticate("deploy", &Secret("hunter2".to_string()), now).is_ok());
+        assert!(users.authenticate("backup", &Secret("correct horse".to_string()), now).is_ok());
+        assert!(users.authenticate("deploy", &Secret("hunter3".to_string()), now).is_err());
+        assert!(users.authenticate("nobody", &Secret("hunter2".to_string()), now).is_err());
+
+        assert!(!users.is_remembered("deploy", &Secret("hunter2".to_string())));
+        users.remember("deploy", &Secret("hunter2".to_string()));
+        assert!(users.is_remembered("deploy", &Secret("hunter2".to_string())));
+        assert!(!users.is_remembered("deploy", &Secret("hunter3".to_string())));
+        assert!(!users.is_remembered("backup", &Secret("hunter2".to_string())));
+
+        // The same secret gets a different salt and hash every time
+        assert!(hash_line("deploy", "hunter2").unwrap() != hash_line("deploy", "hunter2").unwrap());
+        assert_eq!(format!("{:?}", Secret("hunter2".to_string())), "Secret(..)");
+    }
+
+    #[test]
+    fn limit_failed_attempts() {
+        let mut users = users(&[ hash_line("deploy", "hunter2").unwrap() ]);
+        let wrong = Secret("wrong".to_string());
+        let right = Secret("hunter2".to_string());
+        let now   = Instant::now();
+
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(users.authenticate("deploy", &wrong, now), Err("invalid user or secret"));
+        }
+
+        // Even the right secret is refused until the delay has passed
+        assert_eq!(users.authenticate("deploy", &right, now), Err("too many failed attempts, try again later"));
+        assert!(users.authenticate("deploy", &right, now + Duration::from_secs(1)).is_ok());
+
+        // Delays double with every failure
+        let mut now = now + Duration::from_secs(1);
+        for delay in &[ 1, 2, 4 ] {
+            for _ in 0..(if *delay == 1 { FREE_FAILURES } else { 1 }) {
+                assert!(users.authenticate("deploy", &wrong, now).is_err());
+            }
+            now += Duration::from_secs(*delay - 1);
+            assert!(users.authenticate("deploy", &right, now).is_err());
+            now += Duration::from_secs(1);
+        }
+        assert!(users.authenticate("deploy", &right, now).is_ok());
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        let path = env::temp_dir().join(format!("um-test-invalid-{}.users", ::std::process::id()));
+        File::create(&path).unwrap().write_all(b"deploy 10 nothex 00\n").unwrap();
+        assert!(Users::load(&path).is_err());
+        File::create(&path).unwrap().write_all(b"deploy 0 00 00\n").unwrap();
+        assert!(Users::load(&path).is_err());
+        File::create(&path).unwrap().write_all(b"deploy 00 00\n").unwrap();
+        assert!(Users::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
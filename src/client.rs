@@ -1,66 +1,127 @@
+use command::Command;
 use commandcodec::CommandCodec;
+use futures::sync::mpsc::Receiver;
 use futures::{Async, Future, Poll, Stream};
+use protocol::Protocol;
 use response::Response;
-use server::Server;
+use server::{self, ClientId, Sequenced, Server};
 use std::sync::{Arc, Mutex};
-use tokio::io;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{self, AsyncRead, AsyncWrite};
 
 type State = Arc<Mutex<Server>>;
 
-pub struct Client {
-    stream: CommandCodec,
+pub struct Client<T> {
+    id: ClientId,
+    stream: CommandCodec<T>,
     state: State,
+    events: Receiver<Sequenced>,
 }
 
-impl Client {
-    pub fn new(socket: TcpStream, state: State) -> Self {
+impl<T: AsyncRead + AsyncWrite> Client<T> {
+    /// Serves a client that starts out speaking `protocol`.
+    pub fn new(socket: T, state: State, protocol: Protocol) -> Self {
+        let (id, events, limits) = {
+            let mut server = state.lock().unwrap();
+            let (id, events) = server.connect();
+            (id, events, server.limits())
+        };
+
         // Wrap the socket with the `Lines` codec that we wrote above.
-        let stream = CommandCodec::new(socket);
+        let mut stream = CommandCodec::new(socket, limits);
+        stream.set_protocol(protocol);
 
         Client {
-            stream, state,
+            id, stream, state, events,
         }
     }
+
+    /// Executes a command, and buffers the response to it.
+    fn handle(&mut self, tag: Option<&str>, cmd: Result<Command, &'static str>) {
+        println!("Received cmd: {:?}", cmd);
+
+        match cmd {
+            Ok(Command::Auth(user, secret)) => {
+                let response = server::authenticate(&self.state, self.id, user, &secret);
+                self.stream.buffer(tag, response);
+            },
+            Ok(cmd) => {
+                let protocol = if let Command::Proto(protocol) = cmd { Some(protocol) } else { None };
+
+                let mut state = self.state.lock().unwrap();
+                let response = state.execute(self.id, cmd);
+                let switch   = !response.is_err();
+                self.stream.buffer(tag, response);
+                state.notify_watchers();
+
+                // The response to `proto` itself is still in the old protocol
+                if let Some(protocol) = protocol.filter(|_| switch) {
+                    self.stream.set_protocol(protocol);
+                }
+            },
+            Err(e) => {
+                let response = Response::Error(e);
+                self.stream.buffer(tag, response);
+            },
+        };
+    }
 }
 
-impl Future for Client {
+impl<T> Drop for Client<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.disconnect(self.id);
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Future for Client<T> {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<(), io::Error> {
-        // Read new lines from the socket
-        while let Async::Ready(cmd) = self.stream.poll()? {
-            if cmd.is_none() {
-                // EOF was reached. The remote client has disconnected.
-                // There is nothing more to do.
-                println!("client sent EOF");
-                return Ok(Async::Ready(()));
+        loop {
+            // Send what's left from last time first. While too much of it is left, the client
+            // isn't reading what's sent to it, so nothing more is read from or sent to it until
+            // the socket can take more.
+            if self.stream.poll_flush()?.is_not_ready() && self.stream.is_congested() {
+                return Ok(Async::NotReady);
             }
-            let cmd = cmd.unwrap();
-
-            println!("Received cmd: {:?}", cmd);
 
-            match cmd {
-                Ok(cmd) => {
-                    let mut state = self.state.lock().unwrap();
-                    let response = state.store.execute(cmd);
-                    self.stream.buffer(response);
+            // Commands go first, so their responses are sent before the events they cause
+            match self.stream.poll()? {
+                Async::Ready(Some((tag, cmd))) => {
+                    self.handle(tag.as_ref().map(String::as_str), cmd);
+                    continue;
                 },
-                Err(e) => {
-                    let response = Response::Error(e);
-                    self.stream.buffer(response);
+                Async::Ready(None) => {
+                    // EOF was reached. The remote client has disconnected.
+                    // There is nothing more to do.
+                    println!("client sent EOF");
+                    return Ok(Async::Ready(()));
                 },
-            };
+                Async::NotReady => {},
+            }
 
-            self.stream.poll_flush()?;
-        }
+            // Then events for the nodes this client is watching. These may have been sent by
+            // other clients, or by this client while handling the commands above.
+            match self.events.poll().unwrap() {
+                Async::Ready(Some((_, event))) => self.stream.buffer(None, Response::Event(event)),
+                Async::Ready(None) => {
+                    // The server only drops the channel if the client let too many events queue up
+                    println!("client fell too far behind on events");
+                    return Ok(Async::Ready(()));
+                },
+                Async::NotReady => {
+                    self.stream.poll_flush()?;
 
-        // As always, it is important to not just return `NotReady`
-        // without ensuring an inner future also returned `NotReady`.
-        //
-        // We know we got a `NotReady` from either `self.rx` or
-        // `self.lines`, so the contract is respected.
-        Ok(Async::NotReady)
+                    // As always, it is important to not just return `NotReady`
+                    // without ensuring an inner future also returned `NotReady`.
+                    //
+                    // Reading from the socket and `self.events` both returned `NotReady`
+                    // just now, so the task is woken up when either has more.
+                    return Ok(Async::NotReady);
+                },
+            }
+        }
     }
 }
@@ -1,12 +1,246 @@
-use nodespec::NodeSpec;
+use auth::Secret;
+use expiry::Expiry;
+use nodespec::{self, NodeSpec};
+use pattern::Pattern;
+use protocol::Protocol;
+use std::fmt;
 use std::str::FromStr;
 use value::ValType;
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
-    Create(NodeSpec, String, ValType),
+    /// Logs in as a user, which is required before any other command if the server has a
+    /// users file
+    Auth(String, Secret),
+    /// Switches the connection to another protocol
+    Proto(Protocol),
+    /// Creates a node, which may expire, and may be ephemeral (removed when the client that
+    /// created it disconnects)
+    Create(NodeSpec, String, ValType, Option<Expiry>, bool),
     Read(NodeSpec),
-    Update(NodeSpec, String),
+    ReadMatching(Pattern),
+    ReadVersion(NodeSpec),
+    Dump(NodeSpec, Option<usize>),
+    List(NodeSpec, ListOptions),
+    Update(NodeSpec, String, Option<Expiry>),
+    UpdateMatching(Pattern, String),
+    /// Updates a node, or creates it as a string node first if it doesn't exist
+    Set(NodeSpec, String, Option<Expiry>),
+    Cas(NodeSpec, u64, String),
+    Incr(NodeSpec, Option<String>),
+    Decr(NodeSpec, Option<String>),
+    Append(NodeSpec, String),
+    Prepend(NodeSpec, String),
+    Toggle(NodeSpec),
+    /// Adds an item to the end of a list, with the default value for its type unless one is
+    /// given
+    Push(NodeSpec, ValType, Option<String>),
+    /// Adds an item to a list before the given index, counted from the end if negative
+    Insert(NodeSpec, i64, ValType, Option<String>),
+    /// Removes an item from a list, the last one unless an index is given, and returns it
+    Pop(NodeSpec, Option<i64>),
+    Delete(NodeSpec, bool),
+    Ttl(NodeSpec),
+    /// Sets when a node expires, or makes it never expire
+    Expire(NodeSpec, Option<Expiry>),
+    /// Sends events for changes to a node and everything below it. Watching a single list
+    /// item isn't supported, since inserts and pops shift the items after it without events
+    Watch(NodeSpec),
+    Unwatch(NodeSpec),
+    Save(Option<String>),
+    Load(Option<String>),
+    Begin,
+    Commit,
+    Abort,
+}
+
+/// Options for listing the children of a map node.
+#[derive(Debug, Default, PartialEq)]
+pub struct ListOptions {
+    /// Order the children by name
+    pub sort: bool,
+    /// Only list children whose name starts with this
+    pub prefix: Option<String>,
+    /// Only list children after this name, which is the last name of the previous page
+    pub cursor: Option<String>,
+    /// List at most this many children
+    pub limit: Option<usize>,
+}
+
+impl ListOptions {
+    /// Pages can only be continued if the children are listed in a fixed order.
+    pub fn sorted(&self) -> bool {
+        self.sort || self.cursor.is_some() || self.limit.is_some()
+    }
+}
+
+impl Command {
+    /// Returns true if the command changes the store when it succeeds.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Command::Create(..) | Command::Update(..) | Command::Cas(..) | Command::Delete(..) => true,
+            Command::UpdateMatching(..) | Command::Set(..) => true,
+            Command::Incr(..) | Command::Decr(..) | Command::Append(..) | Command::Prepend(..) => true,
+            Command::Toggle(..) | Command::Expire(..) => true,
+            Command::Push(..) | Command::Insert(..) | Command::Pop(..) => true,
+            Command::Read(..) | Command::ReadVersion(..) | Command::Dump(..) => false,
+            Command::List(..) | Command::ReadMatching(..) | Command::Ttl(..) => false,
+            Command::Watch(..) | Command::Unwatch(..)    => false,
+
+            // These are applied to the log as a whole instead
+            Command::Save(..) | Command::Load(..) => false,
+
+            Command::Begin | Command::Commit | Command::Abort => false,
+            Command::Auth(..) | Command::Proto(..) => false,
+        }
+    }
+
+    /// Returns true if the command only operates on the store, which means it can be part of a
+    /// transaction.
+    pub fn is_transactional(&self) -> bool {
+        match self {
+            Command::Create(..) | Command::Read(..) | Command::ReadVersion(..) => true,
+            Command::Dump(..) | Command::List(..)                              => true,
+            Command::ReadMatching(..) | Command::UpdateMatching(..)            => true,
+            Command::Set(..)                                                   => true,
+            Command::Update(..) | Command::Cas(..) | Command::Delete(..)       => true,
+            Command::Incr(..) | Command::Decr(..) | Command::Toggle(..)        => true,
+            Command::Append(..) | Command::Prepend(..)                         => true,
+            Command::Ttl(..) | Command::Expire(..)                             => true,
+            Command::Push(..) | Command::Insert(..) | Command::Pop(..)         => true,
+            _                                                                 => false,
+        }
+    }
+
+    /// Returns the nodespec of the node created by the command, if it's an ephemeral one.
+    pub fn ephemeral_node(&self) -> Option<NodeSpec> {
+        match self {
+            Command::Create(nodespec, name, _, _, true) => {
+                let mut nodespec = nodespec.clone();
+                nodespec.push(name.clone());
+                Some(nodespec)
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the nodespec of the node the command adds a child to, if it does.
+    pub fn parent_node(&self) -> Option<&NodeSpec> {
+        match self {
+            Command::Create(nodespec, ..) | Command::Push(nodespec, ..) | Command::Insert(nodespec, ..) => Some(nodespec),
+            _ => None,
+        }
+    }
+
+    /// Turns expiry times relative to `now` into points in time, so the command has the same
+    /// effect when it's replayed later.
+    pub fn resolve_expiry(&mut self, now: u64) {
+        match self {
+            Command::Create(_, _, _, Some(expiry), _) |
+            Command::Update(_, _, Some(expiry))       |
+            Command::Set(_, _, Some(expiry))          |
+            Command::Expire(_, Some(expiry))          => *expiry = Expiry::At(expiry.deadline(now)),
+            _                                         => (),
+        }
+    }
+}
+
+/// Parses the first argument of a command, which may be preceded by `-t <ttl>`.
+fn ttl_flag<'a, I>(args: &mut I) -> Result<(Option<Expiry>, &'a str), &'static str> where
+    I: Iterator<Item = &'a str>
+{
+    let arg = args.next().ok_or("missing nodespec (1st argument)")?;
+    if arg != "-t" {
+        return Ok((None, arg));
+    }
+
+    let expiry = args.next().ok_or("missing ttl after -t")?.parse()?;
+    let arg    = args.next().ok_or("missing nodespec (3rd argument)")?;
+    Ok((Some(expiry), arg))
+}
+
+impl fmt::Display for Command {
+    /// Formats the command the way `from_str` parses it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Auth(user, secret)              => write!(f, "auth {} :{}", user, secret.0),
+            Command::Proto(protocol)                 => write!(f, "proto {}", protocol),
+            Command::Create(nodespec, name, valtype, expiry, ephemeral) => {
+                write!(f, "create ")?;
+                if *ephemeral {
+                    write!(f, "-e ")?;
+                }
+                if let Some(expiry) = expiry {
+                    write!(f, "-t {} ", expiry)?;
+                }
+                write!(f, "{} {} {}", nodespec, nodespec::escape(name), valtype)
+            },
+            Command::Read(nodespec)                  => write!(f, "read {}", nodespec),
+            Command::ReadMatching(pattern)           => write!(f, "read {}", pattern),
+            Command::ReadVersion(nodespec)           => write!(f, "read -v {}", nodespec),
+            Command::Dump(nodespec, None)            => write!(f, "dump {}", nodespec),
+            Command::Dump(nodespec, Some(depth))     => write!(f, "dump {} {}", nodespec, depth),
+            Command::List(nodespec, opts)            => {
+                write!(f, "list")?;
+                if opts.sort {
+                    write!(f, " -s")?;
+                }
+                if let Some(prefix) = &opts.prefix {
+                    write!(f, " -p {}", nodespec::escape(prefix))?;
+                }
+                if let Some(cursor) = &opts.cursor {
+                    write!(f, " -c {}", nodespec::escape(cursor))?;
+                }
+                if let Some(limit) = opts.limit {
+                    write!(f, " -n {}", limit)?;
+                }
+                write!(f, " {}", nodespec)
+            },
+            Command::Update(nodespec, value, None)   => write!(f, "update {} :{}", nodespec, value),
+            Command::Update(nodespec, value, Some(expiry)) => {
+                write!(f, "update -t {} {} :{}", expiry, nodespec, value)
+            },
+            Command::UpdateMatching(pattern, value)  => write!(f, "update {} :{}", pattern, value),
+            Command::Set(nodespec, value, None)      => write!(f, "set {} :{}", nodespec, value),
+            Command::Set(nodespec, value, Some(expiry)) => {
+                write!(f, "set -t {} {} :{}", expiry, nodespec, value)
+            },
+            Command::Cas(nodespec, version, value)   => write!(f, "cas {} {} :{}", nodespec, version, value),
+            Command::Incr(nodespec, None)            => write!(f, "incr {}", nodespec),
+            Command::Incr(nodespec, Some(amount))    => write!(f, "incr {} {}", nodespec, amount),
+            Command::Decr(nodespec, None)            => write!(f, "decr {}", nodespec),
+            Command::Decr(nodespec, Some(amount))    => write!(f, "decr {} {}", nodespec, amount),
+            Command::Append(nodespec, s)             => write!(f, "append {} :{}", nodespec, s),
+            Command::Prepend(nodespec, s)            => write!(f, "prepend {} :{}", nodespec, s),
+            Command::Toggle(nodespec)                => write!(f, "toggle {}", nodespec),
+            Command::Push(nodespec, valtype, None)   => write!(f, "push {} {}", nodespec, valtype),
+            Command::Push(nodespec, valtype, Some(value)) => {
+                write!(f, "push {} {} :{}", nodespec, valtype, value)
+            },
+            Command::Insert(nodespec, index, valtype, None) => {
+                write!(f, "insert {} {} {}", nodespec, index, valtype)
+            },
+            Command::Insert(nodespec, index, valtype, Some(value)) => {
+                write!(f, "insert {} {} {} :{}", nodespec, index, valtype, value)
+            },
+            Command::Pop(nodespec, None)             => write!(f, "pop {}", nodespec),
+            Command::Pop(nodespec, Some(index))      => write!(f, "pop {} {}", nodespec, index),
+            Command::Delete(nodespec, false)         => write!(f, "delete {}", nodespec),
+            Command::Delete(nodespec, true)          => write!(f, "delete -r {}", nodespec),
+            Command::Ttl(nodespec)                   => write!(f, "ttl {}", nodespec),
+            Command::Expire(nodespec, None)          => write!(f, "ttl {} none", nodespec),
+            Command::Expire(nodespec, Some(expiry))  => write!(f, "ttl {} {}", nodespec, expiry),
+            Command::Watch(nodespec)                 => write!(f, "watch {}", nodespec),
+            Command::Unwatch(nodespec)               => write!(f, "unwatch {}", nodespec),
+            Command::Save(None)                      => write!(f, "save"),
+            Command::Save(Some(path))                => write!(f, "save :{}", path),
+            Command::Load(None)                      => write!(f, "load"),
+            Command::Load(Some(path))                => write!(f, "load :{}", path),
+            Command::Begin                           => write!(f, "begin"),
+            Command::Commit                          => write!(f, "commit"),
+            Command::Abort                           => write!(f, "abort"),
+        }
+    }
 }
 
 impl FromStr for Command {
@@ -17,10 +251,12 @@ impl FromStr for Command {
     ///     command argument argument :final argument
     /// 
     /// The command and arguments are separated with a single space. If the final argument
-    /// is prefixed with a colon (:), it may contain spaces. Normal arguments may not.
+    /// is prefixed with a colon (:), it may contain spaces. Normal arguments may not, unless
+    /// they're escaped with a backslash (in nodespecs and names), where newlines are written as
+    /// `\n` or `\r`. The final argument is taken as is, up to the end of `s`.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let first_space_pos = s.find(' ');    // marks the end of the command
-        let last_argument_pos = s.find(" :"); // marks the start of the last argument
+        let first_space_pos = nodespec::find_unescaped(s, " ");    // marks the end of the command
+        let last_argument_pos = nodespec::find_unescaped(s, " :"); // marks the start of the last argument
 
         let mid_args: Option<&str> = first_space_pos.filter(|_| last_argument_pos.is_none() ||
                                                                 last_argument_pos > first_space_pos)
@@ -28,27 +264,207 @@ impl FromStr for Command {
         let last_arg: Option<&str> = last_argument_pos.map(|p| &s[p+2 .. s.len()]);
 
         let command  = &s[0..first_space_pos.unwrap_or(s.len())];
-        let mut args = mid_args.into_iter().flat_map(|a| a.split(' '))
+        let mut args = mid_args.into_iter().flat_map(|a| nodespec::split_unescaped(a, ' '))
                            .chain(last_arg.into_iter());
 
         match command {
+            "auth" => {
+                let user   = args.next().ok_or("missing user (1st argument)")?.to_string();
+                let secret = args.next().ok_or("missing secret (2nd argument)")?.to_string();
+                if args.next().is_some() { return Err("too many arguments (expected 2)"); }
+                Ok(Command::Auth(user, Secret(secret)))
+            },
+            "proto" => {
+                let protocol = args.next().ok_or("missing protocol (1st argument)")?.parse()?;
+                if args.next().is_some() { return Err("too many arguments (expected 1)"); }
+                Ok(Command::Proto(protocol))
+            },
             "create" => {
-                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
-                let name     = args.next().ok_or("missing name (2nd argument)")?.to_string();
+                let mut expiry    = None;
+                let mut ephemeral = false;
+                let mut arg       = args.next().ok_or("missing nodespec (1st argument)")?;
+                loop {
+                    match arg {
+                        "-t" => expiry    = Some(args.next().ok_or("missing ttl after -t")?.parse()?),
+                        "-e" => ephemeral = true,
+                        _    => break,
+                    }
+                    arg = args.next().ok_or("missing nodespec")?;
+                }
+                let nodespec = arg.parse()?;
+                let name     = nodespec::unescape(args.next().ok_or("missing name (2nd argument)")?)?;
+                if name.is_empty() { return Err("empty name"); }
                 let valtype  = args.next().ok_or("missing valtype (3rd argument)")?.parse()?;
-                if args.next().is_some() { return Err("too many arguments (expected 3)"); }
-                Ok(Command::Create(nodespec, name, valtype))
+                if args.next().is_some() { return Err("too many arguments (expected options and 3 arguments)"); }
+                Ok(Command::Create(nodespec, name, valtype, expiry, ephemeral))
             },
             "read" => {
+                let mut arg = args.next().ok_or("missing nodespec (1st argument)")?;
+                let version = arg == "-v";
+                if version {
+                    arg = args.next().ok_or("missing nodespec (2nd argument)")?;
+                }
+                if args.next().is_some() { return Err("too many arguments (expected 1, or 2 with -v)"); }
+                if Pattern::is_pattern(arg) {
+                    if version { return Err("patterns can't be read with -v"); }
+                    Ok(Command::ReadMatching(arg.parse()?))
+                } else if version {
+                    Ok(Command::ReadVersion(arg.parse()?))
+                } else {
+                    Ok(Command::Read(arg.parse()?))
+                }
+            },
+            "dump" => {
                 let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
-                if args.next().is_some() { return Err("too many arguments (expected 1)"); }
-                Ok(Command::Read(nodespec))
+                let depth    = match args.next() {
+                    Some(d) => Some(d.parse().map_err(|_| "invalid depth")?),
+                    None    => None,
+                };
+                if args.next().is_some() { return Err("too many arguments (expected 1 or 2)"); }
+                Ok(Command::Dump(nodespec, depth))
+            },
+            "list" => {
+                let mut opts = ListOptions::default();
+                let mut arg  = args.next().ok_or("missing nodespec")?;
+                loop {
+                    match arg {
+                        "-s" => opts.sort   = true,
+                        "-p" => opts.prefix = Some(nodespec::unescape(args.next().ok_or("missing prefix after -p")?)?),
+                        "-c" => opts.cursor = Some(nodespec::unescape(args.next().ok_or("missing cursor after -c")?)?),
+                        "-n" => {
+                            let limit = args.next().ok_or("missing limit after -n")?;
+                            opts.limit = Some(limit.parse().ok().filter(|&n| n > 0).ok_or("invalid limit")?);
+                        },
+                        _    => break,
+                    }
+                    arg = args.next().ok_or("missing nodespec")?;
+                }
+                let nodespec = arg.parse()?;
+                if args.next().is_some() { return Err("too many arguments (expected options and a nodespec)"); }
+                Ok(Command::List(nodespec, opts))
             },
             "update" => {
-                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let (expiry, nodespec) = ttl_flag(&mut args)?;
+                let value    = args.next().ok_or("missing value (2nd argument)")?.to_string();
+                if args.next().is_some() { return Err("too many arguments (expected 2, or 4 with -t)"); }
+                if Pattern::is_pattern(nodespec) {
+                    if expiry.is_some() { return Err("patterns can't be updated with -t"); }
+                    Ok(Command::UpdateMatching(nodespec.parse()?, value))
+                } else {
+                    Ok(Command::Update(nodespec.parse()?, value, expiry))
+                }
+            },
+            "set" => {
+                let (expiry, nodespec) = ttl_flag(&mut args)?;
                 let value    = args.next().ok_or("missing value (2nd argument)")?.to_string();
+                if args.next().is_some() { return Err("too many arguments (expected 2, or 4 with -t)"); }
+                Ok(Command::Set(nodespec.parse()?, value, expiry))
+            },
+            "cas" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let version  = args.next().ok_or("missing expected version (2nd argument)")?
+                                   .parse().map_err(|_| "invalid version")?;
+                let value    = args.next().ok_or("missing value (3rd argument)")?.to_string();
+                if args.next().is_some() { return Err("too many arguments (expected 3)"); }
+                Ok(Command::Cas(nodespec, version, value))
+            },
+            "incr" | "decr" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let amount   = args.next().map(|a| a.to_string());
+                if args.next().is_some() { return Err("too many arguments (expected 1 or 2)"); }
+                if command == "incr" {
+                    Ok(Command::Incr(nodespec, amount))
+                } else {
+                    Ok(Command::Decr(nodespec, amount))
+                }
+            },
+            "append" | "prepend" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let s        = args.next().ok_or("missing string (2nd argument)")?.to_string();
                 if args.next().is_some() { return Err("too many arguments (expected 2)"); }
-                Ok(Command::Update(nodespec, value))
+                if command == "append" {
+                    Ok(Command::Append(nodespec, s))
+                } else {
+                    Ok(Command::Prepend(nodespec, s))
+                }
+            },
+            "toggle" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                if args.next().is_some() { return Err("too many arguments (expected 1)"); }
+                Ok(Command::Toggle(nodespec))
+            },
+            "push" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let valtype  = args.next().ok_or("missing valtype (2nd argument)")?.parse()?;
+                let value    = args.next().map(|v| v.to_string());
+                if args.next().is_some() { return Err("too many arguments (expected 2 or 3)"); }
+                Ok(Command::Push(nodespec, valtype, value))
+            },
+            "insert" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let index    = args.next().ok_or("missing index (2nd argument)")?
+                                   .parse().map_err(|_| "invalid index")?;
+                let valtype  = args.next().ok_or("missing valtype (3rd argument)")?.parse()?;
+                let value    = args.next().map(|v| v.to_string());
+                if args.next().is_some() { return Err("too many arguments (expected 3 or 4)"); }
+                Ok(Command::Insert(nodespec, index, valtype, value))
+            },
+            "pop" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let index    = match args.next() {
+                    Some(i) => Some(i.parse().map_err(|_| "invalid index")?),
+                    None    => None,
+                };
+                if args.next().is_some() { return Err("too many arguments (expected 1 or 2)"); }
+                Ok(Command::Pop(nodespec, index))
+            },
+            "delete" => {
+                let mut arg   = args.next().ok_or("missing nodespec (1st argument)")?;
+                let recursive = arg == "-r";
+                if recursive {
+                    arg = args.next().ok_or("missing nodespec (2nd argument)")?;
+                }
+                let nodespec = arg.parse()?;
+                if args.next().is_some() { return Err("too many arguments (expected 1, or 2 with -r)"); }
+                Ok(Command::Delete(nodespec, recursive))
+            },
+            "ttl" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                let expiry   = args.next();
+                if args.next().is_some() { return Err("too many arguments (expected 1 or 2)"); }
+                match expiry {
+                    None         => Ok(Command::Ttl(nodespec)),
+                    Some("none") => Ok(Command::Expire(nodespec, None)),
+                    Some(expiry) => Ok(Command::Expire(nodespec, Some(expiry.parse()?))),
+                }
+            },
+            "watch" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                if args.next().is_some() { return Err("too many arguments (expected 1)"); }
+                Ok(Command::Watch(nodespec))
+            },
+            "unwatch" => {
+                let nodespec = args.next().ok_or("missing nodespec (1st argument)")?.parse()?;
+                if args.next().is_some() { return Err("too many arguments (expected 1)"); }
+                Ok(Command::Unwatch(nodespec))
+            },
+            "save" => {
+                let path = args.next().map(|p| p.to_string());
+                if args.next().is_some() { return Err("too many arguments (expected 0 or 1)"); }
+                Ok(Command::Save(path))
+            },
+            "load" => {
+                let path = args.next().map(|p| p.to_string());
+                if args.next().is_some() { return Err("too many arguments (expected 0 or 1)"); }
+                Ok(Command::Load(path))
+            },
+            "begin" | "commit" | "abort" => {
+                if args.next().is_some() { return Err("too many arguments (expected 0)"); }
+                Ok(match command {
+                    "begin"  => Command::Begin,
+                    "commit" => Command::Commit,
+                    _        => Command::Abort,
+                })
             },
             _ => Err("unknown command"),
         }
@@ -67,6 +483,59 @@ mod tests {
         assert_eq!("read :foo.bar".parse(), Ok(Command::Read("foo.bar".parse().unwrap())));
     }
 
+    #[test]
+    fn parse_dump_command() {
+        assert!("dump".parse::<Command>().is_err());
+        assert!("dump foo deep".parse::<Command>().is_err());
+        assert!("dump foo -1".parse::<Command>().is_err());
+
+        assert_eq!("dump foo".parse(), Ok(Command::Dump("foo".parse().unwrap(), None)));
+        assert_eq!("dump . 2".parse(), Ok(Command::Dump(NodeSpec::root(), Some(2))));
+    }
+
+    #[test]
+    fn parse_list_command() {
+        assert!("list".parse::<Command>().is_err());
+        assert!("list -s".parse::<Command>().is_err());
+        assert!("list -n 0 foo".parse::<Command>().is_err());
+        assert!("list -p".parse::<Command>().is_err());
+        assert!("list foo bar".parse::<Command>().is_err());
+
+        assert_eq!("list foo".parse(), Ok(Command::List("foo".parse().unwrap(), ListOptions::default())));
+        assert_eq!("list -s -p ab -c abc -n 10 .".parse(), Ok(Command::List(NodeSpec::root(), ListOptions {
+            sort:   true,
+            prefix: Some("ab".to_string()),
+            cursor: Some("abc".to_string()),
+            limit:  Some(10),
+        })));
+    }
+
+    #[test]
+    fn parse_patterns() {
+        assert!("read -v foo.*".parse::<Command>().is_err());
+        assert!("read foo.{a,b".parse::<Command>().is_err());
+
+        assert_eq!("read foo.*".parse(), Ok(Command::ReadMatching("foo.*".parse().unwrap())));
+        assert_eq!("update **.port 80".parse(),
+            Ok(Command::UpdateMatching("**.port".parse().unwrap(), "80".to_string())));
+    }
+
+    #[test]
+    fn parse_escaped_arguments() {
+        assert!(r"read foo\".parse::<Command>().is_err());
+        assert!("create .  string".parse::<Command>().is_err());
+
+        let mut ns = NodeSpec::root();
+        ns.push("my key".to_string());
+        assert_eq!(r"create . my\ key string".parse(),
+            Ok(Command::Create(NodeSpec::root(), "my key".to_string(), ValType::String, None, false)));
+        assert_eq!(r"create . example.com string".parse(),
+            Ok(Command::Create(NodeSpec::root(), "example.com".to_string(), ValType::String, None, false)));
+        assert_eq!(r"update my\ key :hello world".parse(),
+            Ok(Command::Update(ns.clone(), "hello world".to_string(), None)));
+        assert_eq!(r"read my\ key".parse(), Ok(Command::Read(ns)));
+    }
+
     #[test]
     fn parse_update_command() {
         assert!("update".parse::<Command>().is_err());
@@ -74,9 +543,192 @@ mod tests {
         assert!("update foo hello world".parse::<Command>().is_err());
 
         assert_eq!("update foo bar".parse(),
-            Ok(Command::Update("foo".parse().unwrap(), "bar".to_string())));
+            Ok(Command::Update("foo".parse().unwrap(), "bar".to_string(), None)));
 
         assert_eq!("update foo :hello world".parse(),
-            Ok(Command::Update("foo".parse().unwrap(), "hello world".to_string())));
+            Ok(Command::Update("foo".parse().unwrap(), "hello world".to_string(), None)));
+
+        // Backslashes in the final argument aren't escapes
+        assert_eq!(r"update foo :C:\new\".parse(),
+            Ok(Command::Update("foo".parse().unwrap(), r"C:\new\".to_string(), None)));
+        assert_eq!("update foo :two\r\nlines".parse(),
+            Ok(Command::Update("foo".parse().unwrap(), "two\r\nlines".to_string(), None)));
+    }
+
+    #[test]
+    fn parse_versioned_commands() {
+        assert!("read -v".parse::<Command>().is_err());
+        assert!("cas foo 3".parse::<Command>().is_err());
+        assert!("cas foo three bar".parse::<Command>().is_err());
+
+        assert_eq!("read -v foo".parse(), Ok(Command::ReadVersion("foo".parse().unwrap())));
+        assert_eq!("cas foo 3 :hello world".parse(),
+            Ok(Command::Cas("foo".parse().unwrap(), 3, "hello world".to_string())));
+    }
+
+    #[test]
+    fn parse_modify_commands() {
+        assert!("incr".parse::<Command>().is_err());
+        assert!("incr foo 1 2".parse::<Command>().is_err());
+        assert!("append foo".parse::<Command>().is_err());
+        assert!("toggle foo bar".parse::<Command>().is_err());
+
+        assert_eq!("incr foo".parse(), Ok(Command::Incr("foo".parse().unwrap(), None)));
+        assert_eq!("decr foo 2.5".parse(), Ok(Command::Decr("foo".parse().unwrap(), Some("2.5".to_string()))));
+        assert_eq!("append foo :, world".parse(),
+            Ok(Command::Append("foo".parse().unwrap(), ", world".to_string())));
+        assert_eq!("prepend foo >".parse(), Ok(Command::Prepend("foo".parse().unwrap(), ">".to_string())));
+        assert_eq!("toggle foo.bar".parse(), Ok(Command::Toggle("foo.bar".parse().unwrap())));
+    }
+
+    #[test]
+    fn parse_list_item_commands() {
+        assert!("push foo".parse::<Command>().is_err());
+        assert!("push foo string a b".parse::<Command>().is_err());
+        assert!("insert foo string".parse::<Command>().is_err());
+        assert!("pop foo last".parse::<Command>().is_err());
+
+        assert_eq!("push foo string :hello world".parse(),
+            Ok(Command::Push("foo".parse().unwrap(), ValType::String, Some("hello world".to_string()))));
+        assert_eq!("push foo map".parse(), Ok(Command::Push("foo".parse().unwrap(), ValType::Map, None)));
+        assert_eq!("insert foo -1 integer 3".parse(),
+            Ok(Command::Insert("foo".parse().unwrap(), -1, ValType::Integer, Some("3".to_string()))));
+        assert_eq!("pop foo".parse(), Ok(Command::Pop("foo".parse().unwrap(), None)));
+        assert_eq!("pop foo -2".parse(), Ok(Command::Pop("foo".parse().unwrap(), Some(-2))));
+
+        for cmd in &["push foo string :a b", "push foo.0 map", "insert foo 2 boolean :true", "pop foo 0"] {
+            assert_eq!(&cmd.parse::<Command>().unwrap().to_string(), cmd);
+        }
+    }
+
+    #[test]
+    fn parse_ttl_commands() {
+        assert!("create -t foo bar string".parse::<Command>().is_err());
+        assert!("create -t 0 foo bar string".parse::<Command>().is_err());
+        assert!("update -t 10 foo.* 1".parse::<Command>().is_err());
+        assert!("ttl foo 10 20".parse::<Command>().is_err());
+
+        assert_eq!("create -t 10 . foo string".parse(),
+            Ok(Command::Create(NodeSpec::root(), "foo".to_string(), ValType::String, Some(Expiry::After(10)), false)));
+        assert_eq!("create -e -t 10 . foo string".parse(),
+            Ok(Command::Create(NodeSpec::root(), "foo".to_string(), ValType::String, Some(Expiry::After(10)), true)));
+        assert!("create -e".parse::<Command>().is_err());
+        assert_eq!("update -t @1000 foo :bar".parse(),
+            Ok(Command::Update("foo".parse().unwrap(), "bar".to_string(), Some(Expiry::At(1000)))));
+        assert_eq!("ttl foo".parse(), Ok(Command::Ttl("foo".parse().unwrap())));
+        assert_eq!("ttl foo none".parse(), Ok(Command::Expire("foo".parse().unwrap(), None)));
+        assert_eq!("ttl foo 5".parse(), Ok(Command::Expire("foo".parse().unwrap(), Some(Expiry::After(5)))));
+
+        let mut cmd: Command = "create -t 10 . foo string".parse().unwrap();
+        cmd.resolve_expiry(5000);
+        assert_eq!(cmd.to_string(), "create -t @15000 . foo string");
+    }
+
+    #[test]
+    fn parse_delete_command() {
+        assert!("delete".parse::<Command>().is_err());
+        assert!("delete -r".parse::<Command>().is_err());
+        assert!("delete foo bar".parse::<Command>().is_err());
+
+        assert_eq!("delete foo.bar".parse(), Ok(Command::Delete("foo.bar".parse().unwrap(), false)));
+        assert_eq!("delete -r foo".parse(), Ok(Command::Delete("foo".parse().unwrap(), true)));
+    }
+
+    #[test]
+    fn parse_snapshot_commands() {
+        assert!("save foo bar".parse::<Command>().is_err());
+
+        assert_eq!("save".parse(), Ok(Command::Save(None)));
+        assert_eq!("load backup.snapshot".parse(), Ok(Command::Load(Some("backup.snapshot".to_string()))));
+        assert_eq!("save :my backup".parse(), Ok(Command::Save(Some("my backup".to_string()))));
+    }
+
+    #[test]
+    fn parse_transaction_commands() {
+        assert!("begin now".parse::<Command>().is_err());
+
+        assert_eq!("begin".parse(), Ok(Command::Begin));
+        assert_eq!("commit".parse(), Ok(Command::Commit));
+        assert_eq!("abort".parse(), Ok(Command::Abort));
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let commands = vec![
+            "create foo.bar baz map",
+            "read .",
+            "read -v foo",
+            r"create example\.com web\ 1 map",
+            r"update example\.com.web\ 1.\:port :8080",
+            r"read \*",
+            "read services.*.{http,https}.**",
+            "update **.enabled :true",
+            "dump .",
+            "dump foo.bar 3",
+            "list foo",
+            "list -s -p a -c ab -n 100 foo.bar",
+            "update foo.bar :hello world",
+            "cas foo 42 :bar",
+            "update foo :",
+            r"update foo :a\nb\\c",
+            "create -t 30 foo bar integer",
+            "create -e foo bar map",
+            "create -e -t @1500000000000 foo bar string",
+            "update -t @1500000000000 foo :bar",
+            "set foo.bar :hello world",
+            "set -t @1500000000000 foo :bar",
+            "ttl foo",
+            "ttl foo 10",
+            "ttl foo none",
+            "incr foo",
+            "decr foo -3",
+            "append foo : bar",
+            "prepend foo :",
+            "toggle foo.bar",
+            "delete foo",
+            "delete -r foo.bar",
+            "watch foo",
+            "unwatch foo",
+            "auth deploy :hunter2",
+            "proto json",
+            "save",
+            "load :/tmp/some backup.snapshot",
+            "begin",
+            "commit",
+            "abort",
+        ];
+
+        for cmd in commands {
+            assert_eq!(cmd.parse::<Command>().unwrap().to_string(), cmd);
+        }
+    }
+
+    #[test]
+    fn parse_auth_command() {
+        assert!("auth deploy".parse::<Command>().is_err());
+        assert!("auth deploy hunter2 extra".parse::<Command>().is_err());
+
+        assert_eq!("auth deploy :correct horse".parse(),
+            Ok(Command::Auth("deploy".to_string(), Secret("correct horse".to_string()))));
+        assert!(!format!("{:?}", "auth deploy hunter2".parse::<Command>().unwrap()).contains("hunter2"));
+    }
+
+    #[test]
+    fn parse_proto_command() {
+        assert!("proto".parse::<Command>().is_err());
+        assert!("proto xml".parse::<Command>().is_err());
+
+        assert_eq!("proto json".parse(), Ok(Command::Proto(Protocol::Json)));
+        assert_eq!("proto line".parse(), Ok(Command::Proto(Protocol::Line)));
+        assert!("proto resp".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn parse_watch_commands() {
+        assert!("watch".parse::<Command>().is_err());
+        assert!("unwatch foo bar".parse::<Command>().is_err());
+
+        assert_eq!("watch foo.bar".parse(), Ok(Command::Watch("foo.bar".parse().unwrap())));
+        assert_eq!("unwatch foo".parse(), Ok(Command::Unwatch("foo".parse().unwrap())));
     }
 }
@@ -1,29 +1,96 @@
-use bytes::{BufMut, BytesMut};
-use command::Command;
+use bytes::BytesMut;
 use futures::{Async, Poll, Stream};
+use protocol::{Protocol, Request};
+use resp;
 use response::Response;
-use std::fmt::Write;
 use tokio::io::{self, AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
 
-pub struct CommandCodec {
-    socket: TcpStream,
+/// Reads commands from, and writes responses to, any transport: one per line, in the
+/// connection's protocol. Lines may end with `\n` or `\r\n`. RESP requests aren't lines, and
+/// are framed the way RESP does it.
+///
+/// A request may have a tag, like `@42 read foo`, which is then sent with the response to it,
+/// so clients don't have to rely on the order of responses.
+pub struct CommandCodec<T> {
+    socket: T,
     rd: BytesMut,
     wr: BytesMut,
+    limits: Limits,
+    protocol: Protocol,
+    /// The names of the RESP commands queued for the current transaction, which decide how
+    /// their results are encoded
+    queued: Vec<String>,
+
+    /// Set after a line that was too long, until the newline that ends it has been read
+    discarding: bool,
+}
+
+/// How much a client may make the server buffer, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    /// The longest line that's accepted as a command, which also limits whole RESP requests
+    pub max_line: usize,
+    /// No more commands are read while this much output is waiting to be written
+    pub max_pending: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_line: 64 * 1024,
+            max_pending: 1024 * 1024,
+        }
+    }
 }
 
-impl CommandCodec {
+impl<T: AsyncRead + AsyncWrite> CommandCodec<T> {
     /// Create a new `CommandCodec` backed by the socket
-    pub fn new(socket: TcpStream) -> Self {
+    pub fn new(socket: T, limits: Limits) -> Self {
         CommandCodec {
             socket,
             rd: BytesMut::new(),
             wr: BytesMut::new(),
+            limits,
+            protocol: Protocol::Line,
+            queued: Vec::new(),
+            discarding: false,
         }
     }
 
-    pub fn buffer(&mut self, response: Response) {
-        self.wr.extend_from_slice(format!("{}\n", response).as_bytes());
+    /// Returns true if so much output is waiting that no more commands should be read.
+    pub fn is_congested(&self) -> bool {
+        self.wr.len() >= self.limits.max_pending
+    }
+
+    /// Reads and writes everything after this in `protocol`.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Buffers a response, tagged with the tag of the request it's for. In the line protocol,
+    /// only the first line of a response that spans multiple lines is tagged.
+    pub fn buffer(&mut self, tag: Option<&str>, response: Response) {
+        let line = match (self.protocol, &response) {
+            (Protocol::Resp, Response::Queued) => {
+                self.queued.extend(tag.map(str::to_string));
+                resp::format_response(tag, &response)
+            },
+            (Protocol::Resp, Response::Results(_, results)) => {
+                let line = resp::format_results(&self.queued, results);
+                self.queued.clear();
+                line
+            },
+            (protocol, _) => {
+                if tag == Some("MULTI") || tag == Some("DISCARD") {
+                    self.queued.clear();
+                }
+                protocol.format_response(tag, &response)
+            },
+        };
+        self.wr.extend_from_slice(line.as_bytes());
+        if self.protocol != Protocol::Resp {
+            self.wr.extend_from_slice(b"\n");
+        }
     }
 
     pub fn poll_flush(&mut self) -> Poll<(), io::Error> {
@@ -44,7 +111,9 @@ impl CommandCodec {
     }
 
     fn fill_read_buf(&mut self) -> Result<Async<()>, io::Error> {
-        loop {
+        // Anything more is left in the socket until the lines that were read have been
+        // handled. The caller gets either a line or an error for it, so it polls again.
+        while self.rd.len() <= self.limits.max_line + 1 {
             // Ensure the read buffer has capacity.
             //
             // This might result in an internal allocation.
@@ -58,18 +127,54 @@ impl CommandCodec {
             if n == 0 {
                 return Ok(Async::Ready(()));
             }
+
+            if self.discarding {
+                self.skip_line();
+            }
         }
+
+        Ok(Async::NotReady)
     }
 
-    fn parse_command(line: &[u8]) -> Result<Command, &'static str> {
-        let line = ::std::str::from_utf8(&line).map_err(|_| "invalid utf-8")?;
-        let cmd  = line.parse()?;
-        Ok(cmd)
+    /// Reads a RESP request, which may span multiple lines. There's no way to find the next
+    /// request after a broken or overlong one, so those close the connection.
+    fn poll_resp(&mut self, sock_closed: bool) -> Poll<Option<Request>, io::Error> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+
+        if let Some(len) = resp::frame_len(&self.rd).map_err(invalid)? {
+            let frame = self.rd.split_to(len);
+            return Ok(Async::Ready(Some(self.protocol.parse_request(&frame))));
+        }
+
+        if self.rd.len() > self.limits.max_line + 1 {
+            return Err(invalid("request too long"));
+        }
+
+        if sock_closed {
+            if !self.rd.is_empty() {
+                println!("client sent EOF in the middle of a command");
+                self.rd.clear();
+            }
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Drops what's left of a line that was too long.
+    fn skip_line(&mut self) {
+        match self.rd.iter().position(|&b| b == b'\n') {
+            Some(pos) => {
+                self.rd.split_to(pos + 1);
+                self.discarding = false;
+            },
+            None      => self.rd.clear(),
+        }
     }
 }
 
-impl Stream for CommandCodec {
-    type Item = Result<Command, &'static str>;
+impl<T: AsyncRead + AsyncWrite> Stream for CommandCodec<T> {
+    type Item = Request;
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
@@ -80,6 +185,10 @@ impl Stream for CommandCodec {
         // to inform the return value below.
         let sock_closed = self.fill_read_buf()?.is_ready();
 
+        if self.protocol == Protocol::Resp {
+            return self.poll_resp(sock_closed);
+        }
+
         // Now, try finding lines
         let pos = self.rd.iter().enumerate()
             .find(|&(_, bytes)| bytes == &b'\n')
@@ -88,22 +197,204 @@ impl Stream for CommandCodec {
         if let Some(pos) = pos {
             // Remove the line from the read buffer and set it
             // to `line`.
-            let mut line = self.rd.split_to(pos + 2);
+            let mut line = self.rd.split_to(pos + 1);
 
-            // Drop the trailing \n
+            // Drop the trailing \n, and the \r before it if there is one
             line.split_off(pos);
+            if line.last() == Some(&b'\r') {
+                line.split_off(pos - 1);
+            }
+
+            if line.len() > self.limits.max_line {
+                return Ok(Async::Ready(Some((self.protocol.parse_tag(&line), Err("line too long")))));
+            }
 
             // Parse the bytes into a `Command`
-            let cmd = Self::parse_command(&line);
+            let request = self.protocol.parse_request(&line);
 
             // Return the line
-            return Ok(Async::Ready(Some(cmd)));
+            return Ok(Async::Ready(Some(request)));
+        }
+
+        // Leave room for a \r that may come before the \n
+        if self.rd.len() > self.limits.max_line + 1 {
+            let tag = self.protocol.parse_tag(&self.rd);
+            self.rd.clear();
+            self.discarding = true;
+            return Ok(Async::Ready(Some((tag, Err("line too long")))));
         }
 
         if sock_closed {
+            // A command that was cut off might mean something else than intended, so it's
+            // not executed
+            if !self.rd.is_empty() {
+                println!("client sent EOF in the middle of a command");
+                self.rd.clear();
+            }
             Ok(Async::Ready(None))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use duplex::{duplex, Duplex, NoNotify};
+    use futures::executor::{self, Spawn};
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+
+    fn codec() -> (Spawn<CommandCodec<Duplex>>, Duplex) {
+        let (server, client) = duplex();
+        (executor::spawn(CommandCodec::new(server, Limits::default())), client)
+    }
+
+    fn limited_codec(max_line: usize) -> (Spawn<CommandCodec<Duplex>>, Duplex) {
+        let (server, client) = duplex();
+        let limits = Limits { max_line, max_pending: 16 };
+        (executor::spawn(CommandCodec::new(server, limits)), client)
+    }
+
+    /// Polls the codec once, returning the command as it would be formatted, `None` at EOF, or
+    /// `"not ready"` if there's no complete line yet.
+    fn next(codec: &mut Spawn<CommandCodec<Duplex>>) -> Option<String> {
+        match codec.poll_stream_notify(&Arc::new(NoNotify), 0).unwrap() {
+            Async::Ready(Some((None, Ok(cmd))))      => Some(cmd.to_string()),
+            Async::Ready(Some((None, Err(e))))       => Some(format!("error: {}", e)),
+            Async::Ready(Some((Some(tag), Ok(cmd)))) => Some(format!("@{} {}", tag, cmd)),
+            Async::Ready(Some((Some(tag), Err(e))))  => Some(format!("@{} error: {}", tag, e)),
+            Async::Ready(None)                       => None,
+            Async::NotReady                          => Some("not ready".to_string()),
+        }
+    }
+
+    #[test]
+    fn partial_lines() {
+        let (mut codec, mut client) = codec();
+        assert_eq!(next(&mut codec), Some("not ready".to_string()));
+
+        client.write_all(b"read f").unwrap();
+        assert_eq!(next(&mut codec), Some("not ready".to_string()));
+        client.write_all(b"oo.b").unwrap();
+        assert_eq!(next(&mut codec), Some("not ready".to_string()));
+        client.write_all(b"ar\nre").unwrap();
+        assert_eq!(next(&mut codec), Some("read foo.bar".to_string()));
+        assert_eq!(next(&mut codec), Some("not ready".to_string()));
+        client.write_all(b"ad baz\n").unwrap();
+        assert_eq!(next(&mut codec), Some("read baz".to_string()));
+    }
+
+    #[test]
+    fn pipelined_commands() {
+        let (mut codec, mut client) = codec();
+        client.write_all(b"begin\ncreate . foo integer\nincr foo\n\ncommit\n").unwrap();
+        client.close();
+
+        // Nothing may be lost between lines, and empty lines are still lines
+        assert_eq!(next(&mut codec), Some("begin".to_string()));
+        assert_eq!(next(&mut codec), Some("create . foo integer".to_string()));
+        assert_eq!(next(&mut codec), Some("incr foo".to_string()));
+        assert_eq!(next(&mut codec), Some("error: unknown command".to_string()));
+        assert_eq!(next(&mut codec), Some("commit".to_string()));
+        assert_eq!(next(&mut codec), None);
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let (mut codec, mut client) = codec();
+        client.write_all(b"update foo :hello world\r\nread foo\r").unwrap();
+        assert_eq!(next(&mut codec), Some("update foo :hello world".to_string()));
+        assert_eq!(next(&mut codec), Some("not ready".to_string()));
+        client.write_all(b"\nupdate foo :\r\r\n").unwrap();
+        assert_eq!(next(&mut codec), Some("read foo".to_string()));
+
+        // Only a single \r is part of the line ending
+        assert_eq!(next(&mut codec), Some("update foo :\r".to_string()));
+    }
+
+    #[test]
+    fn eof_in_the_middle_of_a_line() {
+        let (mut codec, mut client) = codec();
+        client.write_all(b"read foo\ndelete -r fo").unwrap();
+        client.close();
+
+        // The cut off command isn't executed
+        assert_eq!(next(&mut codec), Some("read foo".to_string()));
+        assert_eq!(next(&mut codec), None);
+    }
+
+    #[test]
+    fn long_lines() {
+        let (mut codec, mut client) = limited_codec(16);
+        client.write_all(b"read exactly.16b\nread this.is.too.long").unwrap();
+        assert_eq!(next(&mut codec), Some("read exactly.16b".to_string()));
+        assert_eq!(next(&mut codec), Some("error: line too long".to_string()));
+
+        // The rest of the line is skipped, however long it gets
+        for _ in 0..100 {
+            client.write_all(b".and.longer").unwrap();
+            assert_eq!(next(&mut codec), Some("not ready".to_string()));
+            assert!(codec.get_ref().rd.len() <= 17);
+        }
+        client.write_all(b"\nread foo\n").unwrap();
+        assert_eq!(next(&mut codec), Some("read foo".to_string()));
+
+        // Complete lines are checked as well
+        client.write_all(b"read this.is.too.long\nread foo\n").unwrap();
+        assert_eq!(next(&mut codec), Some("error: line too long".to_string()));
+        assert_eq!(next(&mut codec), Some("read foo".to_string()));
+
+        // Lines that are only too long with their \r\n are fine
+        client.write_all(b"read exactly.16b\r\nread foo.bar.baz.qux").unwrap();
+        client.close();
+        assert_eq!(next(&mut codec), Some("read exactly.16b".to_string()));
+        assert_eq!(next(&mut codec), Some("error: line too long".to_string()));
+        assert_eq!(next(&mut codec), None);
+    }
+
+    #[test]
+    fn pipelined_lines_are_not_limited() {
+        let (mut codec, mut client) = limited_codec(16);
+        for _ in 0..10 {
+            client.write_all(b"read foo\n").unwrap();
+        }
+        for _ in 0..10 {
+            assert_eq!(next(&mut codec), Some("read foo".to_string()));
+        }
+        assert_eq!(next(&mut codec), Some("not ready".to_string()));
+    }
+
+    #[test]
+    fn tagged_commands() {
+        let (mut codec, mut client) = limited_codec(32);
+        client.write_all(b"@42 read foo\r\n@x-1 ttl foo @1000\n@7 frobnicate\n@ read foo\n@9\n").unwrap();
+        client.write_all(b"@long read this.is.much.too.long.for.a.line\n").unwrap();
+        client.write_all(b"@longer read this.line.is.long.and.cut.off").unwrap();
+        client.close();
+
+        assert_eq!(next(&mut codec), Some("@42 read foo".to_string()));
+        assert_eq!(next(&mut codec), Some("@x-1 ttl foo @1000".to_string()));
+        assert_eq!(next(&mut codec), Some("@7 error: unknown command".to_string()));
+        assert_eq!(next(&mut codec), Some("error: unknown command".to_string()));
+        assert_eq!(next(&mut codec), Some("@9 error: unknown command".to_string()));
+        assert_eq!(next(&mut codec), Some("@long error: line too long".to_string()));
+        assert_eq!(next(&mut codec), Some("@longer error: line too long".to_string()));
+        assert_eq!(next(&mut codec), None);
+    }
+
+    #[test]
+    fn write_responses() {
+        let (mut codec, mut client) = codec();
+        codec.get_mut().buffer(None, Response::Success);
+        codec.get_mut().buffer(Some("7"), Response::Error("node does not exist"));
+        codec.get_mut().buffer(Some("a.b"), Response::Ttl(None));
+        assert!(codec.get_mut().poll_flush().unwrap().is_ready());
+        drop(codec);
+
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "success\n@7 error :node does not exist\n@a.b ttl none\n");
+    }
+}
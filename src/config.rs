@@ -0,0 +1,269 @@
+use commandcodec::Limits;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+usage: um [options]
+
+options:
+    --config <file>       read options from a file (one `option = value` per line)
+    --listen <address>    listen on a TCP address (host:port), a TCP address with TLS
+                          (tls:host:port), a TCP address for Redis clients (resp:host:port),
+                          an HTTP gateway (http:host:port) or a Unix domain socket
+                          (unix:/path/to/socket); may be given more than once
+    --socket-mode <mode>  permissions of Unix domain sockets, in octal (e.g. 660)
+    --log <file>          keep the write-ahead log in this file (default: um.log)
+    --load <file>         load a snapshot if the log is new or empty, so it seeds the log
+                          instead of replacing what was logged since
+    --snapshot-dir <dir>  directory that clients save snapshots to and load them from
+                          (default: .)
+    --tls-cert <file>     certificate chain for TLS listeners, in PEM format
+    --tls-key <file>      private key for TLS listeners, in PEM format
+    --tls-client-ca <file>
+                          only accept TLS clients with a certificate signed by a CA in this file
+    --max-line-length <bytes>
+                          reject commands longer than this (default: 65536)
+    --max-pending-output <bytes>
+                          stop reading commands from clients that have this much output
+                          waiting to be sent to them (default: 1048576)
+    --users <file>        require clients to authenticate as one of the users in this file
+    --acl <file>          only allow what the rules in this file grant to each user
+    --hash-password <user>
+                          read a secret from stdin and print a line for the users file
+    --help                show this message";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Tls(SocketAddr),
+    Resp(SocketAddr),
+    Http(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub listen: Vec<Listen>,
+    pub socket_mode: Option<u32>,
+    pub log: PathBuf,
+    pub load: Option<PathBuf>,
+    pub snapshot_dir: PathBuf,
+    pub users: Option<PathBuf>,
+    pub acl: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub limits: Limits,
+    /// Print a line for the users file for this user, instead of starting the server
+    pub hash_password: Option<String>,
+}
+
+impl Config {
+    /// Builds the configuration from command line arguments (without the program name).
+    /// Options read from a `--config` file can be overridden by options after it.
+    pub fn from_args<I>(args: I) -> Result<Config, String> where
+        I: IntoIterator<Item = String>
+    {
+        let mut config = Config {
+            listen: Vec::new(),
+            socket_mode: None,
+            log: PathBuf::from("um.log"),
+            load: None,
+            snapshot_dir: PathBuf::from("."),
+            users: None,
+            acl: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            limits: Limits::default(),
+            hash_password: None,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                return Err(format!("unexpected argument '{}'\n\n{}", arg, USAGE));
+            }
+
+            let value = args.next().ok_or_else(|| format!("missing value for {}", arg))?;
+            if arg == "--config" {
+                config.read_file(Path::new(&value))?;
+            } else {
+                config.set(&arg[2..], &value)?;
+            }
+        }
+
+        if config.listen.is_empty() {
+            config.listen.push(Listen::Tcp("127.0.0.1:3535".parse().unwrap()));
+        }
+
+        let tls = config.listen.iter().any(|l| if let Listen::Tls(_) = l { true } else { false });
+        if tls && (config.tls_cert.is_none() || config.tls_key.is_none()) {
+            return Err("TLS listeners need --tls-cert and --tls-key".to_string());
+        }
+
+        Ok(config)
+    }
+
+    /// Reads options from a file. Every line holds a single `option = value` pair, using the
+    /// same names as the command line options. Empty lines and lines starting with `#` are
+    /// ignored.
+    fn read_file(&mut self, path: &Path) -> Result<(), String> {
+        let file = File::open(path).map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let eq = line.find('=').ok_or_else(|| format!("{}:{}: expected `option = value`", path.display(), i + 1))?;
+            self.set(line[..eq].trim(), line[eq + 1..].trim())
+                .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        match option {
+            "listen"             => self.listen.push(value.parse()?),
+            "socket-mode"        => {
+                let mode = u32::from_str_radix(value, 8).map_err(|_| format!("invalid socket mode '{}'", value))?;
+                self.socket_mode = Some(mode);
+            },
+            "log"                => self.log  = PathBuf::from(value),
+            "load"               => self.load = Some(PathBuf::from(value)),
+            "snapshot-dir"       => self.snapshot_dir = PathBuf::from(value),
+            "users"              => self.users = Some(PathBuf::from(value)),
+            "acl"                => self.acl   = Some(PathBuf::from(value)),
+            "tls-cert"           => self.tls_cert = Some(PathBuf::from(value)),
+            "tls-key"            => self.tls_key  = Some(PathBuf::from(value)),
+            "tls-client-ca"      => self.tls_client_ca = Some(PathBuf::from(value)),
+            "max-line-length"    => self.limits.max_line    = parse_size(value)?,
+            "max-pending-output" => self.limits.max_pending = parse_size(value)?,
+            "hash-password"      => self.hash_password = Some(value.to_string()),
+            _                    => return Err(format!("unknown option '{}'", option)),
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    value.parse().ok().filter(|&n| n > 0).ok_or_else(|| format!("invalid size '{}'", value))
+}
+
+impl ::std::str::FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("unix:") {
+            Ok(Listen::Unix(PathBuf::from(&s[5..])))
+        } else if s.starts_with("tls:") {
+            s[4..].parse().map(Listen::Tls).map_err(|_| format!("invalid address '{}'", s))
+        } else if s.starts_with("resp:") {
+            s[5..].parse().map(Listen::Resp).map_err(|_| format!("invalid address '{}'", s))
+        } else if s.starts_with("http:") {
+            s[5..].parse().map(Listen::Http).map_err(|_| format!("invalid address '{}'", s))
+        } else {
+            s.parse().map(Listen::Tcp).map_err(|_| format!("invalid address '{}'", s))
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr)  => write!(f, "{}", addr),
+            Listen::Tls(addr)  => write!(f, "tls:{}", addr),
+            Listen::Resp(addr) => write!(f, "resp:{}", addr),
+            Listen::Http(addr) => write!(f, "http:{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split(' ').filter(|a| !a.is_empty()).map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn default_config() {
+        let config = Config::from_args(args("")).unwrap();
+        assert_eq!(config.listen, vec![ Listen::Tcp("127.0.0.1:3535".parse().unwrap()) ]);
+        assert_eq!(config.log, PathBuf::from("um.log"));
+        assert_eq!(config.load, None);
+        assert_eq!(config.snapshot_dir, PathBuf::from("."));
+        assert_eq!(config.users, None);
+        assert_eq!(config.acl, None);
+        assert_eq!(config.limits, Limits::default());
+    }
+
+    #[test]
+    fn parse_args() {
+        let config = Config::from_args(args("--listen 0.0.0.0:1234 --listen unix:/run/um.sock --listen resp:[::1]:6379 --listen http:127.0.0.1:8080 --socket-mode 660 --acl um.acl")).unwrap();
+        assert_eq!(config.listen, vec![
+            Listen::Tcp("0.0.0.0:1234".parse().unwrap()),
+            Listen::Unix(PathBuf::from("/run/um.sock")),
+            Listen::Resp("[::1]:6379".parse().unwrap()),
+            Listen::Http("127.0.0.1:8080".parse().unwrap()),
+        ]);
+        assert_eq!(config.socket_mode, Some(0o660));
+        assert_eq!(config.acl, Some(PathBuf::from("um.acl")));
+
+        assert!(Config::from_args(args("--listen")).is_err());
+        assert!(Config::from_args(args("--listen localhost")).is_err());
+        assert!(Config::from_args(args("--socket-mode 999")).is_err());
+        assert!(Config::from_args(args("--frobnicate yes")).is_err());
+        assert!(Config::from_args(args("stray")).is_err());
+
+        let config = Config::from_args(args("--listen tls:0.0.0.0:3536 --tls-cert um.crt --tls-key um.key")).unwrap();
+        assert_eq!(config.listen, vec![ Listen::Tls("0.0.0.0:3536".parse().unwrap()) ]);
+        assert_eq!(config.tls_client_ca, None);
+        assert!(Config::from_args(args("--listen tls:0.0.0.0:3536 --tls-cert um.crt")).is_err());
+
+        let config = Config::from_args(args("--max-line-length 1024 --max-pending-output 4096")).unwrap();
+        assert_eq!(config.limits, Limits { max_line: 1024, max_pending: 4096 });
+        assert!(Config::from_args(args("--max-line-length 0")).is_err());
+        assert!(Config::from_args(args("--max-pending-output lots")).is_err());
+
+        let config = Config::from_args(args("--log other.log --hash-password deploy")).unwrap();
+        assert_eq!(config.hash_password, Some("deploy".to_string()));
+        assert!(Config::from_args(args("--hash-password")).is_err());
+    }
+
+    #[test]
+    fn read_config_file() {
+        let path = env::temp_dir().join(format!("um-test-{}.conf", ::std::process::id()));
+        File::create(&path).unwrap().write_all(b"\
+            # local agents only\n\
+            listen = unix:/tmp/um.sock\n\
+            \n\
+            log = /var/lib/um/um.log\n\
+            snapshot-dir = /var/lib/um\n\
+            users = /etc/um/users\n").unwrap();
+
+        let config = Config::from_args(vec![
+            "--config".to_string(), path.to_string_lossy().into_owned(),
+            "--log".to_string(), "other.log".to_string(),
+        ]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.listen, vec![ Listen::Unix(PathBuf::from("/tmp/um.sock")) ]);
+        assert_eq!(config.log, PathBuf::from("other.log"));
+        assert_eq!(config.snapshot_dir, PathBuf::from("/var/lib/um"));
+        assert_eq!(config.users, Some(PathBuf::from("/etc/um/users")));
+    }
+}
@@ -0,0 +1,132 @@
+use futures::executor::Notify;
+use futures::task::{self, Task};
+use futures::{Async, Poll};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// One end of an in-memory connection, so clients can be tested without a socket. Bytes
+/// written to one end can be read from the other.
+pub struct Duplex {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+}
+
+struct Pipe {
+    buf: VecDeque<u8>,
+    /// Set when the writing end has shut down or was dropped
+    closed: bool,
+    /// The task that's waiting for bytes to read
+    reader: Option<Task>,
+    /// How many bytes can be waiting to be read before writes block
+    capacity: usize,
+    /// The task that's waiting for room to write
+    writer: Option<Task>,
+}
+
+/// Ignores wakeups, for tests that poll futures by hand.
+pub struct NoNotify;
+
+impl Notify for NoNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+/// Returns both ends of a new connection.
+pub fn duplex() -> (Duplex, Duplex) {
+    duplex_with_capacity(usize::max_value())
+}
+
+/// Returns both ends of a new connection, which only buffers `capacity` bytes in each
+/// direction.
+pub fn duplex_with_capacity(capacity: usize) -> (Duplex, Duplex) {
+    let a = Arc::new(Mutex::new(Pipe::new(capacity)));
+    let b = Arc::new(Mutex::new(Pipe::new(capacity)));
+
+    (Duplex { incoming: a.clone(), outgoing: b.clone() }, Duplex { incoming: b, outgoing: a })
+}
+
+impl Duplex {
+    /// Stops writing, so the other end reads EOF once it has read everything before it.
+    pub fn close(&mut self) {
+        let mut pipe = self.outgoing.lock().unwrap();
+        pipe.closed = true;
+        pipe.notify();
+    }
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Pipe {
+        Pipe {
+            buf: VecDeque::new(),
+            closed: false,
+            reader: None,
+            capacity,
+            writer: None,
+        }
+    }
+
+    fn notify(&mut self) {
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.incoming.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Ok(0);
+            }
+            pipe.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let n = buf.len().min(pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        if let Some(task) = pipe.writer.take() {
+            task.notify();
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.outgoing.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let n = buf.len().min(pipe.capacity - pipe.buf.len());
+        if n == 0 && !buf.is_empty() {
+            pipe.writer = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        pipe.buf.extend(&buf[..n]);
+        pipe.notify();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Duplex {}
+
+impl AsyncWrite for Duplex {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
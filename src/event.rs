@@ -0,0 +1,54 @@
+use nodespec::NodeSpec;
+use std::fmt;
+use value::Value;
+
+/// A change to the store, as sent to clients watching the affected node.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Create(NodeSpec, Value),
+    Update(NodeSpec, Value),
+    Delete(NodeSpec),
+}
+
+impl Event {
+    pub fn nodespec(&self) -> &NodeSpec {
+        match self {
+            Event::Create(nodespec, _) => nodespec,
+            Event::Update(nodespec, _) => nodespec,
+            Event::Delete(nodespec)    => nodespec,
+        }
+    }
+
+    /// Returns true if a client watching `watch` should be notified of this event. This is the
+    /// case when the changed node is the watched node or lies below it, but also when it lies
+    /// above it, since creating or deleting a map affects everything in it.
+    pub fn affects(&self, watch: &NodeSpec) -> bool {
+        let nodespec = self.nodespec();
+        nodespec.starts_with(watch) || watch.starts_with(nodespec)
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Create(nodespec, val) => write!(f, "create {} {}", nodespec, val),
+            Event::Update(nodespec, val) => write!(f, "update {} {}", nodespec, val),
+            Event::Delete(nodespec)      => write!(f, "delete {}", nodespec),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_affects_watchers() {
+        let event = Event::Delete("foo.bar".parse().unwrap());
+
+        assert!(event.affects(&"foo.bar".parse().unwrap()));
+        assert!(event.affects(&"foo".parse().unwrap()));
+        assert!(event.affects(&"foo.bar.baz".parse().unwrap()));
+        assert!(!event.affects(&"foo.baz".parse().unwrap()));
+    }
+}
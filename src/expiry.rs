@@ -0,0 +1,69 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When a node expires, either as a number of seconds from now, or as a point in time written
+/// as `@<milliseconds since the unix epoch>`.
+///
+/// Commands are logged with the point in time, so replaying them doesn't extend the lifetime
+/// of their nodes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    After(u64),
+    At(u64),
+}
+
+impl Expiry {
+    /// Returns the point in time the node expires, in milliseconds since the unix epoch.
+    pub fn deadline(&self, now: u64) -> u64 {
+        match *self {
+            Expiry::After(secs) => now.saturating_add(secs.saturating_mul(1000)),
+            Expiry::At(ms)      => ms,
+        }
+    }
+}
+
+/// Returns the current time in milliseconds since the unix epoch.
+pub fn now() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000)
+}
+
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expiry::After(secs) => write!(f, "{}", secs),
+            Expiry::At(ms)      => write!(f, "@{}", ms),
+        }
+    }
+}
+
+impl FromStr for Expiry {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('@') {
+            s[1..].parse().map(Expiry::At).map_err(|_| "invalid expiry time")
+        } else {
+            s.parse().ok().filter(|&secs| secs > 0).map(Expiry::After).ok_or("invalid ttl")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expiry() {
+        assert_eq!("30".parse(), Ok(Expiry::After(30)));
+        assert_eq!("@1500000000000".parse(), Ok(Expiry::At(1500000000000)));
+        assert!("0".parse::<Expiry>().is_err());
+        assert!("-5".parse::<Expiry>().is_err());
+        assert!("@soon".parse::<Expiry>().is_err());
+
+        assert_eq!(Expiry::After(30).deadline(1000), 31000);
+        assert_eq!(Expiry::At(5).deadline(1000), 5);
+        assert_eq!(Expiry::At(5).to_string(), "@5");
+    }
+}
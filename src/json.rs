@@ -0,0 +1,377 @@
+use auth::Secret;
+use command::{Command, ListOptions};
+use event::Event;
+use expiry::Expiry;
+use nodespec::NodeSpec;
+use protocol::Request;
+use response::Response;
+use serde_json::{self, Map, Number, Value as Json};
+use std::str;
+use value::{ValType, Value};
+
+/// Parses a request like `{"command": "update", "nodespec": ["foo", "bar"], "value": 3}`.
+///
+/// The fields are named after the arguments and flags of the line protocol. Nodespecs are
+/// arrays of names, patterns are strings in the same syntax as in the line protocol, and
+/// values may be strings, numbers or booleans. Any `tag` is sent back with the response.
+///
+/// Like in the line protocol, values are parsed according to the type of the node they're
+/// stored in, so numbers and booleans are taken as their text: `true` stores the string `true`
+/// in a string node. Only `push` and `insert`, which name the type themselves, reject numbers
+/// and booleans that don't fit it.
+pub fn parse_request(line: &[u8]) -> Request {
+    let request = match str::from_utf8(line).ok().and_then(|l| serde_json::from_str::<Json>(l).ok()) {
+        Some(Json::Object(request)) => request,
+        Some(_)                     => return (None, Err("request is not a json object")),
+        None                        => return (None, Err("invalid json")),
+    };
+
+    let tag = request.get("tag").map(|tag| tag.to_string());
+    (tag, Fields(&request).command())
+}
+
+/// Formats a response as an object with a `status` that matches the first word of the line
+/// protocol's response, like `{"status": "value", "type": "integer", "value": 3}`.
+pub fn format_response(tag: Option<&str>, response: &Response) -> String {
+    let mut obj = response_object(response);
+    if let Some(tag) = tag {
+        obj.insert("tag".to_string(), serde_json::from_str(tag).unwrap_or(Json::Null));
+    }
+
+    Json::Object(obj).to_string()
+}
+
+fn response_object(response: &Response) -> Map<String, Json> {
+    let mut obj = Map::new();
+    let status = match response {
+        Response::Success              => "success",
+        Response::Value(val)           => {
+            add_value(&mut obj, val);
+            "value"
+        },
+        Response::Popped(val)          => {
+            add_value(&mut obj, val);
+            "value"
+        },
+        Response::Versioned(version, val) => {
+            obj.insert("version".to_string(), json!(version));
+            add_value(&mut obj, val);
+            "version"
+        },
+        Response::Tree(nodes)          => {
+            let nodes = nodes.iter().map(|(nodespec, val)| {
+                let mut node = Map::new();
+                node.insert("nodespec".to_string(), nodespec_json(nodespec));
+                add_value(&mut node, val);
+                Json::Object(node)
+            });
+            obj.insert("nodes".to_string(), Json::Array(nodes.collect()));
+            "tree"
+        },
+        Response::List(children, next) => {
+            let children = children.iter().map(|(name, valtype)| {
+                json!({ "name": name, "type": valtype.to_string() })
+            });
+            obj.insert("children".to_string(), Json::Array(children.collect()));
+            obj.insert("next".to_string(), json!(next));
+            "list"
+        },
+        Response::Error(err)           => {
+            obj.insert("error".to_string(), json!(err));
+            "error"
+        },
+        Response::Event(event)         => {
+            let (kind, nodespec, val) = match event {
+                Event::Create(nodespec, val) => ("create", nodespec, Some(val)),
+                Event::Update(nodespec, val) => ("update", nodespec, Some(val)),
+                Event::Delete(nodespec)      => ("delete", nodespec, None),
+            };
+            obj.insert("event".to_string(), json!(kind));
+            obj.insert("nodespec".to_string(), nodespec_json(nodespec));
+            if let Some(val) = val {
+                add_value(&mut obj, val);
+            }
+            "event"
+        },
+        Response::Queued               => "queued",
+        Response::Ttl(ttl)             => {
+            obj.insert("ttl".to_string(), json!(ttl));
+            "ttl"
+        },
+        Response::Results(committed, results) => {
+            let results = results.iter().map(|res| Json::Object(response_object(&res.as_response())));
+            obj.insert("results".to_string(), Json::Array(results.collect()));
+            if *committed { "committed" } else { "aborted" }
+        },
+    };
+
+    obj.insert("status".to_string(), json!(status));
+    obj
+}
+
+/// Adds the type and value of a node. Maps and lists get the number of children they have
+/// instead of a value, and floats that json can't represent are written as strings.
+fn add_value(obj: &mut Map<String, Json>, val: &Value) {
+    obj.insert("type".to_string(), json!(val.valtype().to_string()));
+    let (key, val) = match val {
+        Value::Empty      => ("value", Json::Null),
+        Value::Boolean(b) => ("value", json!(b)),
+        Value::Integer(i) => ("value", json!(i)),
+        Value::Float(f)   => ("value", Number::from_f64(*f).map(Json::Number).unwrap_or_else(|| json!(f.to_string()))),
+        Value::String(s)  => ("value", json!(s)),
+        Value::Map(m)     => ("size",  json!(m.len())),
+        Value::List(l)    => ("size",  json!(l.len())),
+    };
+    obj.insert(key.to_string(), val);
+}
+
+fn nodespec_json(nodespec: &NodeSpec) -> Json {
+    Json::Array(nodespec.iter().map(|name| json!(name)).collect())
+}
+
+/// The fields of a request.
+struct Fields<'a>(&'a Map<String, Json>);
+
+impl<'a> Fields<'a> {
+    fn command(&self) -> Result<Command, &'static str> {
+        let command = self.string("command")?.ok_or("missing command")?;
+
+        Ok(match command {
+            "auth"    => {
+                let user   = self.string("user")?.ok_or("missing user")?.to_string();
+                let secret = self.string("secret")?.ok_or("missing secret")?.to_string();
+                Command::Auth(user, Secret(secret))
+            },
+            "proto"   => Command::Proto(self.string("protocol")?.ok_or("missing protocol")?.parse()?),
+            "create"  => {
+                let name = self.string("name")?.ok_or("missing name")?.to_string();
+                if name.is_empty() { return Err("empty name"); }
+                let valtype = self.string("type")?.ok_or("missing type")?.parse()?;
+                Command::Create(self.nodespec()?, name, valtype, self.expiry()?, self.flag("ephemeral")?)
+            },
+            "read"    => match self.string("pattern")? {
+                Some(pattern) => Command::ReadMatching(pattern.parse()?),
+                None if self.flag("version")? => Command::ReadVersion(self.nodespec()?),
+                None          => Command::Read(self.nodespec()?),
+            },
+            "dump"    => Command::Dump(self.nodespec()?, self.number("depth")?.map(|d| d as usize)),
+            "list"    => Command::List(self.nodespec()?, ListOptions {
+                sort:   self.flag("sort")?,
+                prefix: self.string("prefix")?.map(str::to_string),
+                cursor: self.string("cursor")?.map(str::to_string),
+                limit:  match self.number("limit")? {
+                    Some(0)     => return Err("invalid limit"),
+                    limit       => limit.map(|n| n as usize),
+                },
+            }),
+            "update"  => match self.string("pattern")? {
+                Some(pattern) => Command::UpdateMatching(pattern.parse()?, self.value("value")?),
+                None          => Command::Update(self.nodespec()?, self.value("value")?, self.expiry()?),
+            },
+            "cas"     => {
+                let version = self.number("version")?.ok_or("missing version")?;
+                Command::Cas(self.nodespec()?, version, self.value("value")?)
+            },
+            "incr"    => Command::Incr(self.nodespec()?, self.optional_value("amount")?),
+            "decr"    => Command::Decr(self.nodespec()?, self.optional_value("amount")?),
+            "append"  => Command::Append(self.nodespec()?, self.value("value")?),
+            "prepend" => Command::Prepend(self.nodespec()?, self.value("value")?),
+            "toggle"  => Command::Toggle(self.nodespec()?),
+            "push"    => {
+                let valtype = self.string("type")?.ok_or("missing type")?.parse()?;
+                Command::Push(self.nodespec()?, valtype, self.typed_value("value", valtype)?)
+            },
+            "insert"  => {
+                let index   = self.index()?.ok_or("missing index")?;
+                let valtype = self.string("type")?.ok_or("missing type")?.parse()?;
+                Command::Insert(self.nodespec()?, index, valtype, self.typed_value("value", valtype)?)
+            },
+            "pop"     => Command::Pop(self.nodespec()?, self.index()?),
+            "delete"  => Command::Delete(self.nodespec()?, self.flag("recursive")?),
+            "ttl"     => match self.0.get("ttl") {
+                // Unlike a missing ttl, null makes the node never expire
+                Some(Json::Null) => Command::Expire(self.nodespec()?, None),
+                _                => match self.expiry()? {
+                    Some(expiry) => Command::Expire(self.nodespec()?, Some(expiry)),
+                    None         => Command::Ttl(self.nodespec()?),
+                },
+            },
+            "watch"   => Command::Watch(self.nodespec()?),
+            "unwatch" => Command::Unwatch(self.nodespec()?),
+            "save"    => Command::Save(self.string("path")?.map(str::to_string)),
+            "load"    => Command::Load(self.string("path")?.map(str::to_string)),
+            "begin"   => Command::Begin,
+            "commit"  => Command::Commit,
+            "abort"   => Command::Abort,
+            _         => return Err("unknown command"),
+        })
+    }
+
+    fn string(&self, key: &str) -> Result<Option<&'a str>, &'static str> {
+        match self.0.get(key) {
+            Some(Json::String(s)) => Ok(Some(s)),
+            Some(_)               => Err("expected a string"),
+            None                  => Ok(None),
+        }
+    }
+
+    fn number(&self, key: &str) -> Result<Option<u64>, &'static str> {
+        match self.0.get(key) {
+            Some(n) => n.as_u64().map(Some).ok_or("expected a non-negative integer"),
+            None    => Ok(None),
+        }
+    }
+
+    /// Reads the index of a list item, which counts from the end if negative.
+    fn index(&self) -> Result<Option<i64>, &'static str> {
+        match self.0.get("index") {
+            Some(n) => n.as_i64().map(Some).ok_or("expected an integer"),
+            None    => Ok(None),
+        }
+    }
+
+    fn flag(&self, key: &str) -> Result<bool, &'static str> {
+        match self.0.get(key) {
+            Some(Json::Bool(b)) => Ok(*b),
+            Some(_)             => Err("expected a boolean"),
+            None                => Ok(false),
+        }
+    }
+
+    fn nodespec(&self) -> Result<NodeSpec, &'static str> {
+        let names = match self.0.get("nodespec") {
+            Some(Json::Array(names)) => names,
+            Some(_)                  => return Err("nodespec must be an array of names"),
+            None                     => return Err("missing nodespec"),
+        };
+
+        let mut nodespec = NodeSpec::root();
+        for name in names {
+            match name {
+                Json::String(name) if !name.is_empty() => nodespec.push(name.clone()),
+                Json::String(_)                        => return Err("empty name in nodespec"),
+                _                                      => return Err("nodespec must be an array of names"),
+            }
+        }
+        Ok(nodespec)
+    }
+
+    /// Returns a value the way the line protocol would have it.
+    fn optional_value(&self, key: &str) -> Result<Option<String>, &'static str> {
+        match self.0.get(key) {
+            Some(Json::String(s)) => Ok(Some(s.clone())),
+            Some(Json::Bool(b))   => Ok(Some(b.to_string())),
+            Some(Json::Number(n)) => Ok(Some(n.to_string())),
+            Some(_)               => Err("expected a string, number or boolean"),
+            None                  => Ok(None),
+        }
+    }
+
+    /// Returns a value for a node of type `valtype`.
+    fn typed_value(&self, key: &str, valtype: ValType) -> Result<Option<String>, &'static str> {
+        match (self.0.get(key), valtype) {
+            (Some(Json::Bool(_)), ValType::Boolean)  => {},
+            (Some(Json::Number(_)), ValType::Integer) |
+            (Some(Json::Number(_)), ValType::Float)  => {},
+            (Some(Json::Bool(_)), _) |
+            (Some(Json::Number(_)), _)               => return Err("expected a value of the given type"),
+            _                                        => {},
+        }
+        self.optional_value(key)
+    }
+
+    fn value(&self, key: &str) -> Result<String, &'static str> {
+        self.optional_value(key)?.ok_or("missing value")
+    }
+
+    /// Reads `ttl` (in seconds) or `expires` (in milliseconds since the unix epoch).
+    fn expiry(&self) -> Result<Option<Expiry>, &'static str> {
+        match (self.number("ttl")?, self.number("expires")?) {
+            (Some(0), _)       => Err("invalid ttl"),
+            (Some(_), Some(_)) => Err("ttl and expires can't both be given"),
+            (Some(secs), None) => Ok(Some(Expiry::After(secs))),
+            (None, Some(ms))   => Ok(Some(Expiry::At(ms))),
+            (None, None)       => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use response::Outcome;
+
+    fn parse(s: &str) -> Result<String, &'static str> {
+        parse_request(s.as_bytes()).1.map(|cmd| cmd.to_string())
+    }
+
+    #[test]
+    fn parse_requests() {
+        assert_eq!(parse(r#"{"command": "read", "nodespec": ["example.com", "web 1"]}"#),
+                   Ok(r"read example\.com.web\ 1".to_string()));
+        assert_eq!(parse(r#"{"command": "read", "nodespec": []}"#), Ok("read .".to_string()));
+        assert_eq!(parse(r#"{"command": "read", "pattern": "services.*"}"#), Ok("read services.*".to_string()));
+        assert_eq!(parse(r#"{"command": "create", "nodespec": [], "name": "a b", "type": "integer", "ephemeral": true, "ttl": 5}"#),
+                   Ok(r"create -e -t 5 . a\ b integer".to_string()));
+        assert_eq!(parse(r#"{"command": "update", "nodespec": ["foo"], "value": 3.5, "expires": 1000}"#),
+                   Ok("update -t @1000 foo :3.5".to_string()));
+        assert_eq!(parse(r#"{"command": "cas", "nodespec": ["foo"], "version": 2, "value": true}"#),
+                   Ok("cas foo 2 :true".to_string()));
+        assert_eq!(parse(r#"{"command": "list", "nodespec": ["foo"], "sort": true, "limit": 10}"#),
+                   Ok("list -s -n 10 foo".to_string()));
+        assert_eq!(parse(r#"{"command": "ttl", "nodespec": ["foo"], "ttl": null}"#), Ok("ttl foo none".to_string()));
+        assert_eq!(parse(r#"{"command": "ttl", "nodespec": ["foo"]}"#), Ok("ttl foo".to_string()));
+        assert_eq!(parse(r#"{"command": "proto", "protocol": "line"}"#), Ok("proto line".to_string()));
+        assert_eq!(parse(r#"{"command": "push", "nodespec": ["jobs"], "type": "string", "value": "a b"}"#),
+                   Ok("push jobs string :a b".to_string()));
+        assert_eq!(parse(r#"{"command": "insert", "nodespec": ["jobs"], "index": -1, "type": "map"}"#),
+                   Ok("insert jobs -1 map".to_string()));
+        assert_eq!(parse(r#"{"command": "insert", "nodespec": ["jobs"], "index": 0, "type": "integer", "value": 5}"#),
+                   Ok("insert jobs 0 integer :5".to_string()));
+        assert_eq!(parse(r#"{"command": "pop", "nodespec": ["jobs"]}"#), Ok("pop jobs".to_string()));
+        assert_eq!(parse(r#"{"command": "update", "nodespec": ["a\nb"], "value": "two\nlines"}"#),
+                   Ok("update a\\nb :two\nlines".to_string()));
+
+        assert_eq!(parse("read foo"), Err("invalid json"));
+        assert_eq!(parse("[]"), Err("request is not a json object"));
+        assert_eq!(parse(r#"{"command": "read", "nodespec": "foo"}"#), Err("nodespec must be an array of names"));
+        assert_eq!(parse(r#"{"command": "read", "nodespec": ["foo", ""]}"#), Err("empty name in nodespec"));
+        assert_eq!(parse(r#"{"command": "update", "nodespec": ["foo"], "value": [1]}"#),
+                   Err("expected a string, number or boolean"));
+        assert_eq!(parse(r#"{"command": "push", "nodespec": ["jobs"], "type": "string", "value": 5}"#),
+                   Err("expected a value of the given type"));
+        assert_eq!(parse(r#"{"command": "push", "nodespec": ["jobs"], "type": "float", "value": true}"#),
+                   Err("expected a value of the given type"));
+        assert_eq!(parse(r#"{"command": "pop", "nodespec": ["jobs"], "index": "0"}"#), Err("expected an integer"));
+        assert_eq!(parse(r#"{"command": "frobnicate"}"#), Err("unknown command"));
+
+        let (tag, _) = parse_request(br#"{"command": "begin", "tag": {"id": 7}}"#);
+        assert_eq!(tag, Some(r#"{"id":7}"#.to_string()));
+    }
+
+    #[test]
+    fn format_responses() {
+        let int  = Value::Integer(3);
+        let nan  = Value::Float(::std::f64::NAN);
+        let text = Value::String("a \"quoted\"\nline".to_string());
+
+        assert_eq!(format_response(None, &Response::Success), r#"{"status":"success"}"#);
+        assert_eq!(format_response(Some("42"), &Response::Value(&int)),
+                   r#"{"status":"value","tag":42,"type":"integer","value":3}"#);
+        assert_eq!(format_response(None, &Response::Value(&text)),
+                   r#"{"status":"value","type":"string","value":"a \"quoted\"\nline"}"#);
+        assert_eq!(format_response(None, &Response::Versioned(2, &nan)),
+                   r#"{"status":"version","type":"float","value":"NaN","version":2}"#);
+        assert_eq!(format_response(None, &Response::Tree(vec![ ("a.b".parse().unwrap(), &int) ])),
+                   r#"{"nodes":[{"nodespec":["a","b"],"type":"integer","value":3}],"status":"tree"}"#);
+        assert_eq!(format_response(None, &Response::List(vec![ ("a", ValType::Map) ], Some("a"))),
+                   r#"{"children":[{"name":"a","type":"map"}],"next":"a","status":"list"}"#);
+        assert_eq!(format_response(None, &Response::Error("node does not exist")),
+                   r#"{"error":"node does not exist","status":"error"}"#);
+        assert_eq!(format_response(None, &Response::Event(Event::Delete("a".parse().unwrap()))),
+                   r#"{"event":"delete","nodespec":["a"],"status":"event"}"#);
+        assert_eq!(format_response(None, &Response::Ttl(None)), r#"{"status":"ttl","ttl":null}"#);
+        assert_eq!(format_response(None, &Response::Results(false, vec![ Outcome::Value(Value::Integer(3)), Outcome::Other(Response::Error("node does not exist")) ])),
+                   r#"{"results":[{"status":"value","type":"integer","value":3},{"error":"node does not exist","status":"error"}],"status":"aborted"}"#);
+    }
+}
@@ -2,20 +2,130 @@
 extern crate bytes;
 #[macro_use]
 extern crate futures;
+extern crate httparse;
+extern crate openssl;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
 extern crate tokio;
+extern crate tokio_uds;
 
+mod acl;
+mod auth;
 mod client;
 mod command;
 mod commandcodec;
+mod config;
+#[cfg(test)]
+mod duplex;
+mod event;
+mod expiry;
+mod http;
+mod json;
 mod node;
 mod nodespec;
+mod pattern;
+mod protocol;
+mod resp;
 mod response;
 mod server;
+mod snapshot;
 mod store;
+mod tls;
 mod value;
+mod wal;
 
+use acl::Acl;
+use auth::Users;
+use config::{Config, USAGE};
 use server::Server;
+use std::env;
+use std::io::{self, BufRead};
+use std::process;
 
 fn main() {
-    Server::new().run();
+    if env::args().any(|arg| arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let config = Config::from_args(env::args().skip(1)).unwrap_or_else(|e| {
+        println!("{}", e);
+        process::exit(2);
+    });
+
+    if let Some(ref user) = config.hash_password {
+        hash_password(user);
+        return;
+    }
+
+    let mut server = Server::open(&config.log).unwrap_or_else(|e| {
+        println!("failed to open {}: {}", config.log.display(), e);
+        process::exit(1);
+    });
+
+    // The option usually stays in the config file, so the snapshot is only loaded into a new
+    // log. Loading it again on later starts would throw away everything logged since.
+    match config.load {
+        Some(ref path) if server.log_is_empty() => {
+            server.load_snapshot(path).unwrap_or_else(|e| {
+                println!("failed to load snapshot {}: {}", path.display(), e);
+                process::exit(1);
+            });
+        },
+        Some(ref path) => {
+            println!("not loading snapshot {}, since {} isn't empty", path.display(), config.log.display());
+        },
+        None => {},
+    }
+
+    server.set_limits(config.limits);
+    server.set_snapshot_dir(config.snapshot_dir.clone());
+
+    if let Some(ref path) = config.users {
+        let users = Users::load(path).unwrap_or_else(|e| {
+            println!("failed to load users from {}: {}", path.display(), e);
+            process::exit(1);
+        });
+        server.require_auth(users);
+    }
+
+    if let Some(ref path) = config.acl {
+        let acl = Acl::load(path).unwrap_or_else(|e| {
+            println!("failed to load access control list from {}: {}", path.display(), e);
+            process::exit(1);
+        });
+        server.restrict_access(acl);
+    }
+
+    if let (Some(ref cert), Some(ref key)) = (&config.tls_cert, &config.tls_key) {
+        let acceptor = tls::acceptor(cert, key, config.tls_client_ca.as_ref().map(|p| p.as_path())).unwrap_or_else(|e| {
+            println!("failed to set up TLS: {}", e);
+            process::exit(1);
+        });
+        server.enable_tls(acceptor);
+    }
+
+    if let Err(e) = server.run(&config.listen, config.socket_mode) {
+        println!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Prints a line for the users file, for the secret read from stdin.
+fn hash_password(user: &str) {
+    let mut secret = String::new();
+    let stdin = io::stdin();
+    if let Err(e) = stdin.lock().read_line(&mut secret) {
+        println!("can't read secret: {}", e);
+        process::exit(1);
+    }
+
+    match auth::hash_line(user, secret.trim_end_matches(|c| c == '\n' || c == '\r')) {
+        Ok(line) => println!("{}", line),
+        Err(e)   => {
+            println!("can't generate salt: {}", e);
+            process::exit(1);
+        },
+    }
 }
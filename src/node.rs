@@ -1,14 +1,22 @@
 use value::{Map, ValType, Value};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Node {
     value: Value,
+    version: u64,
+    /// When the node expires, in milliseconds since the unix epoch
+    expires: Option<u64>,
+    /// Ephemeral nodes are removed when the client that created them disconnects
+    ephemeral: bool,
 }
 
 impl Node {
     pub fn with_value(value: Value) -> Node {
         Node {
             value,
+            version: 0,
+            expires: None,
+            ephemeral: false,
         }
     }
 
@@ -20,6 +28,7 @@ impl Node {
             ValType::Float   => Value::Float(0.0),
             ValType::String  => Value::String(String::new()),
             ValType::Map     => Value::Map(Map::new()),
+            ValType::List    => Value::List(Vec::new()),
         })
     }
 
@@ -35,8 +44,46 @@ impl Node {
         self.value()
     }
 
-    pub fn update_value(&mut self, s: &str) -> Result<(), &'static str> {
-        self.value = Value::from_str(s, &self.value.valtype())?;
+    pub fn into_value(self) -> Value {
+        self.value
+    }
+
+    /// Returns the number of times the value has been updated since the node was created.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+
+    pub fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+
+    pub fn set_expires(&mut self, expires: Option<u64>) {
+        self.expires = expires;
+    }
+
+    /// Returns true if the node has expired at `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |t| t <= now)
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    pub fn set_ephemeral(&mut self, ephemeral: bool) {
+        self.ephemeral = ephemeral;
+    }
+
+    /// Replaces the value with the one computed from it by `f`.
+    pub fn modify_value<F>(&mut self, f: F) -> Result<(), &'static str> where
+        F: FnOnce(&Value) -> Result<Value, &'static str>
+    {
+        self.value = f(&self.value)?;
+        self.version += 1;
         Ok(())
     }
 }
@@ -48,4 +95,18 @@ mod tests {
     #[test]
     fn parse_simple_nodespec() {
     }
+
+    #[test]
+    fn versions() {
+        let mut node = Node::with_type(&ValType::Integer);
+        assert_eq!(node.version(), 0);
+
+        node.modify_value(|_| Ok(Value::Integer(1))).unwrap();
+        node.modify_value(|val| val.add("1", false)).unwrap();
+        assert_eq!(node.version(), 2);
+
+        // Failed updates don't count
+        assert!(node.modify_value(|val| val.add("three", false)).is_err());
+        assert_eq!(node.version(), 2);
+    }
 }
@@ -1,17 +1,44 @@
+use std::fmt;
 use std::iter::IntoIterator;
 use std::str::FromStr;
 
 type Iter<'a> = ::std::slice::Iter<'a, String>;
 
-#[derive(Debug, PartialEq)]
+/// Characters that have to be escaped with a backslash to be part of a name: the separator,
+/// characters with a special meaning in commands and patterns, and the backslash itself.
+const SPECIAL: &[char] = &[ '\\', '.', ' ', ':', '*', '{', '}', ',' ];
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct NodeSpec {
     path: Vec<String>,
 }
 
 impl NodeSpec {
+    pub fn root() -> NodeSpec {
+        NodeSpec {
+            path: vec![],
+        }
+    }
+
     pub fn iter(&self) -> Iter {
         self.path.iter()
     }
+
+    /// Removes the last element of the path and returns it, turning the nodespec into the
+    /// nodespec of its parent. Returns `None` for the root node.
+    pub fn pop(&mut self) -> Option<String> {
+        self.path.pop()
+    }
+
+    /// Appends a child name to the path, turning the nodespec into the nodespec of that child.
+    pub fn push(&mut self, name: String) {
+        self.path.push(name)
+    }
+
+    /// Returns true if `prefix` refers to this node or one of its ancestors.
+    pub fn starts_with(&self, prefix: &NodeSpec) -> bool {
+        self.path.len() >= prefix.path.len() && self.path[..prefix.path.len()] == prefix.path[..]
+    }
 }
 
 impl IntoIterator for NodeSpec {
@@ -23,18 +50,109 @@ impl IntoIterator for NodeSpec {
     }
 }
 
+/// Escapes every special character in `name`, so it can be used as a segment of a nodespec.
+/// Newlines are written as `\n` and `\r`, since commands and responses end at them.
+pub fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _    => {
+                if SPECIAL.contains(&c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            },
+        }
+    }
+    escaped
+}
+
+/// Resolves the escapes in a single segment of a nodespec.
+pub fn unescape(s: &str) -> Result<String, &'static str> {
+    let mut name  = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next().ok_or("trailing backslash in nodespec")? {
+                'n' => name.push('\n'),
+                'r' => name.push('\r'),
+                c   => name.push(c),
+            }
+        } else {
+            name.push(c);
+        }
+    }
+    Ok(name)
+}
+
+/// Escapes the newlines in the final argument of a response, which ends at the first newline,
+/// as `\n` and `\r`. Backslashes are left alone, so a value containing `\n` looks the same as one
+/// containing a newline.
+pub fn escape_value(s: &str) -> String {
+    s.replace('\n', "\\n").replace('\r', "\\r")
+}
+
+/// Returns the byte position of the first occurrence of `pat` in `s` that doesn't start with
+/// an escaped character.
+pub fn find_unescaped(s: &str, pat: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if s[i..].starts_with(pat) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Splits `s` on every occurrence of `sep` that isn't escaped. Escapes are left in place.
+pub fn split_unescaped(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest  = s;
+    while let Some(pos) = find_unescaped(rest, sep.encode_utf8(&mut [0; 4])) {
+        parts.push(&rest[..pos]);
+        rest = &rest[pos + sep.len_utf8()..];
+    }
+    parts.push(rest);
+    parts
+}
+
+impl fmt::Display for NodeSpec {
+    /// Formats the nodespec the way `from_str` parses it, escaping special characters in names.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, ".")
+        } else {
+            let escaped: Vec<String> = self.path.iter().map(|name| escape(name)).collect();
+            write!(f, "{}", escaped.join("."))
+        }
+    }
+}
+
 impl FromStr for NodeSpec {
     type Err = &'static str;
 
+    /// Nodespecs are names separated by dots, or a single dot for the root node. A backslash
+    /// escapes the character after it, so names can contain dots, spaces and so on.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let path: Vec<String> =
-            if s == "." {
-                vec![]
-            } else {
-                s.split('.')
-                    .map(|s| s.to_string())
-                    .collect()
-            };
+        if s.is_empty() {
+            return Err("empty nodespec");
+        }
+
+        let mut path = vec![];
+        if s != "." {
+            for segment in split_unescaped(s, '.') {
+                if segment.is_empty() {
+                    return Err("empty name in nodespec");
+                }
+                path.push(unescape(segment)?);
+            }
+        }
 
         Ok(NodeSpec {
             path,
@@ -56,6 +174,63 @@ mod tests {
             Ok(vec![ "foo".into(), "bar".into() ]));
     }
 
+    #[test]
+    fn display_nodespec() {
+        assert_eq!(".".parse::<NodeSpec>().unwrap().to_string(), ".");
+        assert_eq!("foo.bar".parse::<NodeSpec>().unwrap().to_string(), "foo.bar");
+    }
+
+    #[test]
+    fn escaped_nodespec() {
+        assert_eq!(r"example\.com.port".parse::<NodeSpec>().map(|n| n.path),
+            Ok(vec![ "example.com".into(), "port".into() ]));
+        assert_eq!(r"my\ key.a\\b".parse::<NodeSpec>().map(|n| n.path),
+            Ok(vec![ "my key".into(), r"a\b".into() ]));
+
+        assert!("".parse::<NodeSpec>().is_err());
+        assert!("a..b".parse::<NodeSpec>().is_err());
+        assert!(".foo".parse::<NodeSpec>().is_err());
+        assert!("foo.".parse::<NodeSpec>().is_err());
+        assert!(r"foo\".parse::<NodeSpec>().is_err());
+
+        let mut ns = NodeSpec::root();
+        for name in &[ "example.com", "with space", r"back\slash", "*{a,b}:" ] {
+            ns.push(name.to_string());
+        }
+        assert_eq!(ns.to_string(), r"example\.com.with\ space.back\\slash.\*\{a\,b\}\:");
+        assert_eq!(ns.to_string().parse(), Ok(ns));
+
+        let ns: NodeSpec = "a\r\nb".parse().unwrap();
+        assert_eq!(ns.to_string(), r"a\r\nb");
+        assert_eq!(ns.to_string().parse(), Ok(ns));
+    }
+
+    #[test]
+    fn escaped_values() {
+        assert_eq!(escape_value("a\r\nb"), r"a\r\nb");
+        assert_eq!(escape_value(r"C:\new\"), r"C:\new\");
+    }
+
+    #[test]
+    fn split_escaped() {
+        assert_eq!(split_unescaped(r"a\ b c", ' '), vec![ r"a\ b", "c" ]);
+        assert_eq!(split_unescaped(r"a\\ b", ' '), vec![ r"a\\", "b" ]);
+        assert_eq!(find_unescaped(r"a\ :b :c", " :"), Some(5));
+    }
+
+    #[test]
+    fn nodespec_prefixes() {
+        let foo: NodeSpec    = "foo".parse().unwrap();
+        let foobar: NodeSpec = "foo.bar".parse().unwrap();
+        let root: NodeSpec   = ".".parse().unwrap();
+
+        assert!(foobar.starts_with(&foo));
+        assert!(foobar.starts_with(&foobar));
+        assert!(foobar.starts_with(&root));
+        assert!(!foo.starts_with(&foobar));
+        assert!(!foo.starts_with(&"foobar".parse().unwrap()));
+    }
+
     // #[test]
     // fn peek_and_shift() {
     //     let mut ns: NodeSpec = "foo.bar.foobar".parse().unwrap();
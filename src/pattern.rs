@@ -0,0 +1,252 @@
+use nodespec::{self, find_unescaped, split_unescaped, NodeSpec};
+use std::fmt;
+use std::str::FromStr;
+
+/// How many alternatives the braces in a pattern may expand to in total, since every
+/// combination of them is kept.
+const MAX_ALTERNATIVES: usize = 1024;
+
+/// A nodespec that can match more than one node. Every segment is either a plain name, a glob
+/// like `serv*` or `{http,https}` that matches a single level, or `**`, which matches any
+/// number of levels (including none).
+#[derive(Debug, PartialEq)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    Name(String),
+    /// The glob as written, and the alternatives its braces expand to
+    Glob(String, Vec<Vec<GlobPart>>),
+    AnyDepth,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GlobPart {
+    Char(char),
+    /// `*`, which matches any (possibly empty) sequence of characters
+    Star,
+}
+
+impl Pattern {
+    /// Returns true if `s` should be parsed as a pattern instead of a plain nodespec.
+    pub fn is_pattern(s: &str) -> bool {
+        [ "*", "{", "}" ].iter().any(|c| find_unescaped(s, c).is_some())
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Returns the node all matches are below, which is named by the segments before the
+    /// first glob.
+    pub fn prefix(&self) -> NodeSpec {
+        let mut prefix = NodeSpec::root();
+        for segment in &self.segments {
+            match segment {
+                Segment::Name(name) => prefix.push(name.clone()),
+                _                   => break,
+            }
+        }
+        prefix
+    }
+}
+
+impl Segment {
+    /// Returns true if a child named `name` matches this segment. `AnyDepth` matches every
+    /// name.
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Segment::Name(n)       => n == name,
+            Segment::Glob(_, alts) => {
+                let name: Vec<char> = name.chars().collect();
+                alts.iter().any(|alt| glob_matches(alt, &name))
+            },
+            Segment::AnyDepth      => true,
+        }
+    }
+}
+
+/// Matches a glob against a name. After a mismatch, only the last star is retried with one more
+/// character, which keeps this at O(glob × name) steps however many stars there are.
+fn glob_matches(glob: &[GlobPart], name: &[char]) -> bool {
+    let (mut g, mut n) = (0, 0);
+    // The part after the last star, and how much of the name that star has taken
+    let mut star = None;
+
+    while n < name.len() {
+        match glob.get(g) {
+            Some(GlobPart::Star) => {
+                star = Some((g + 1, n));
+                g += 1;
+            },
+            Some(GlobPart::Char(c)) if *c == name[n] => {
+                g += 1;
+                n += 1;
+            },
+            _ => match star {
+                Some((after, taken)) => {
+                    star = Some((after, taken + 1));
+                    g = after;
+                    n = taken + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|part| *part == GlobPart::Star)
+}
+
+/// Expands every `{a,b}` group in `s` into all combinations of alternatives. Escapes are left
+/// in place.
+fn expand_braces(s: &str) -> Result<Vec<String>, &'static str> {
+    let open = match find_unescaped(s, "{") {
+        Some(open) => open,
+        None if find_unescaped(s, "}").is_some() => return Err("unmatched brace in pattern"),
+        None       => return Ok(vec![ s.to_string() ]),
+    };
+    let close = find_unescaped(&s[open..], "}").map(|p| open + p).ok_or("unmatched brace in pattern")?;
+    let inner = &s[open + 1..close];
+    if find_unescaped(inner, "{").is_some() {
+        return Err("nested braces are not supported in patterns");
+    }
+
+    let alts = split_unescaped(inner, ',');
+    let rests = expand_braces(&s[close + 1..])?;
+    if alts.len() * rests.len() > MAX_ALTERNATIVES {
+        return Err("pattern has too many alternatives");
+    }
+
+    let mut expanded = Vec::new();
+    for rest in rests {
+        for alt in &alts {
+            expanded.push(format!("{}{}{}", &s[..open], alt, rest));
+        }
+    }
+    Ok(expanded)
+}
+
+/// Parses a single alternative of a glob, resolving its escapes.
+fn parse_glob(s: &str) -> Result<Vec<GlobPart>, &'static str> {
+    let mut parts = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        parts.push(match c {
+            '\\' => GlobPart::Char(chars.next().ok_or("trailing backslash in nodespec")?),
+            '*'  => GlobPart::Star,
+            c    => GlobPart::Char(c),
+        });
+    }
+    Ok(parts)
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            match segment {
+                Segment::Name(name)    => write!(f, "{}", nodespec::escape(name))?,
+                Segment::Glob(glob, _) => write!(f, "{}", glob)?,
+                Segment::AnyDepth      => write!(f, "**")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Alternatives can only contain dots if they're escaped, like names
+        let mut segments = Vec::new();
+        let mut alternatives = 0;
+        for segment in split_unescaped(s, '.') {
+            segments.push(match segment {
+                ""   => return Err("empty name in nodespec"),
+                "**" => Segment::AnyDepth,
+                _ if Pattern::is_pattern(segment) => {
+                    let alts: Vec<_> = expand_braces(segment)?.iter()
+                        .map(|alt| parse_glob(alt))
+                        .collect::<Result<_, _>>()?;
+                    alternatives += alts.len();
+                    if alternatives > MAX_ALTERNATIVES {
+                        return Err("pattern has too many alternatives");
+                    }
+                    Segment::Glob(segment.to_string(), alts)
+                },
+                _    => Segment::Name(nodespec::unescape(segment)?),
+            });
+        }
+
+        Ok(Pattern {
+            segments,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pattern() {
+        let pattern: Pattern = "services.*.{http,https}.**".parse().unwrap();
+        assert_eq!(pattern.segments(), &[
+            Segment::Name("services".to_string()),
+            Segment::Glob("*".to_string(), vec![ vec![ GlobPart::Star ] ]),
+            Segment::Glob("{http,https}".to_string(), vec![
+                "http".chars().map(GlobPart::Char).collect(),
+                "https".chars().map(GlobPart::Char).collect(),
+            ]),
+            Segment::AnyDepth,
+        ]);
+        assert_eq!(pattern.to_string(), "services.*.{http,https}.**");
+        assert_eq!(pattern.prefix(), "services".parse().unwrap());
+        assert_eq!("**.port".parse::<Pattern>().unwrap().prefix(), NodeSpec::root());
+
+        assert!("foo.{a,b".parse::<Pattern>().is_err());
+        assert!("foo.a}".parse::<Pattern>().is_err());
+        assert!("foo.{a,{b,c}}".parse::<Pattern>().is_err());
+        assert!("foo..*".parse::<Pattern>().is_err());
+
+        // Braces multiply, so they're limited before they're expanded
+        assert!("{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}".parse::<Pattern>().is_ok());
+        assert_eq!("x{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}".parse::<Pattern>(),
+                   Err("pattern has too many alternatives"));
+        assert_eq!("{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}{a,b}.{a,b}".parse::<Pattern>(),
+                   Err("pattern has too many alternatives"));
+    }
+
+    #[test]
+    fn match_segments() {
+        let glob = |s: &str| s.parse::<Pattern>().unwrap().segments.remove(0);
+
+        assert!(glob("*").matches("anything"));
+        assert!(glob("*").matches(""));
+        assert!(glob("web-*").matches("web-1"));
+        assert!(!glob("web-*").matches("db-1"));
+        assert!(glob("*-{1,2}").matches("web-2"));
+        assert!(!glob("*-{1,2}").matches("web-3"));
+        assert!(glob("{a,b}{c,d}").matches("bc"));
+        assert!(!glob("{a,b}{c,d}").matches("ab"));
+        assert!(glob("*a*b").matches("xaxbxb"));
+        assert!(!glob("*a*b").matches("xaxbxa"));
+
+        // Stars don't backtrack into each other
+        let name = "a".repeat(1000);
+        assert!(!glob("*a*a*a*a*a*a*a*a*a*a*a*a*b").matches(&name));
+        assert!(glob("*a*a*a*a*a*a*a*a*a*a*a*a*").matches(&name));
+
+        // Escaped characters only match themselves
+        assert!(glob(r"\**").matches("*foo"));
+        assert!(!glob(r"\**").matches("foo"));
+        assert!(glob(r"{a\.b,c\,d}").matches("a.b"));
+        assert!(glob(r"{a\.b,c\,d}").matches("c,d"));
+        assert!(!Pattern::is_pattern(r"foo.\*"));
+    }
+}
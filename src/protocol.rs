@@ -0,0 +1,88 @@
+use command::Command;
+use json;
+use resp;
+use response::Response;
+use std::fmt;
+use std::str::{self, FromStr};
+
+/// A command read from a line, with the tag its response should be sent with, if any. RESP
+/// requests are tagged with the name of their command instead.
+pub type Request = (Option<String>, Result<Command, &'static str>);
+
+/// The way commands and responses are written. Clients start out with the line protocol, or with
+/// RESP on RESP listeners, and can switch with `proto json`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// Commands as parsed by `Command::from_str`, and responses as formatted by `Response`'s
+    /// `Display`
+    Line,
+    /// A JSON object per command and response
+    Json,
+    /// The Redis serialization protocol, for Redis clients. Its commands map onto ours, and
+    /// aren't limited to a line.
+    Resp,
+}
+
+impl Protocol {
+    pub fn parse_request(&self, line: &[u8]) -> Request {
+        match self {
+            Protocol::Line => {
+                let tag  = self.parse_tag(line);
+                let line = match tag {
+                    Some(ref tag) => line.get(tag.len() + 2..).unwrap_or(b""),
+                    None          => line,
+                };
+
+                let cmd = str::from_utf8(line).map_err(|_| "invalid utf-8").and_then(str::parse);
+                (tag, cmd)
+            },
+            Protocol::Json => json::parse_request(line),
+            Protocol::Resp => resp::parse_request(line),
+        }
+    }
+
+    /// Returns the tag at the start of a line, which may not be complete yet. JSON requests
+    /// can't be read until they're complete, so their tags can't be found this way.
+    pub fn parse_tag(&self, line: &[u8]) -> Option<String> {
+        if *self != Protocol::Line || line.first() != Some(&b'@') {
+            return None;
+        }
+
+        let end = line.iter().position(|&b| b == b' ').unwrap_or(line.len());
+        str::from_utf8(&line[1..end]).ok().filter(|tag| !tag.is_empty()).map(str::to_string)
+    }
+
+    /// Formats a response, without the newline after it. RESP replies end in their own `\r\n`.
+    pub fn format_response(&self, tag: Option<&str>, response: &Response) -> String {
+        match (self, tag) {
+            (Protocol::Line, Some(tag)) => format!("@{} {}", tag, response),
+            (Protocol::Line, None)      => response.to_string(),
+            (Protocol::Json, tag)       => json::format_response(tag, response),
+            (Protocol::Resp, command)   => resp::format_response(command, response),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Line => write!(f, "line"),
+            Protocol::Json => write!(f, "json"),
+            Protocol::Resp => write!(f, "resp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = &'static str;
+
+    /// Only the protocols clients can switch to with `proto`. RESP clients connect to their
+    /// own listener, and couldn't switch back from it.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(Protocol::Line),
+            "json" => Ok(Protocol::Json),
+            _      => Err("unknown protocol"),
+        }
+    }
+}
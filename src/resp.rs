@@ -0,0 +1,299 @@
+use auth::Secret;
+use command::Command;
+use expiry::Expiry;
+use nodespec::NodeSpec;
+use protocol::Request;
+use response::{Outcome, Response};
+use std::str;
+use value::Value;
+
+/// Returns the length of the request at the start of `buf`, or `None` if it isn't complete yet.
+///
+/// Requests are arrays of bulk strings, like client libraries send them, or inline commands,
+/// which are a line of words like `GET foo` that can be typed into telnet.
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, &'static str> {
+    Ok(split_frame(buf)?.map(|(_, len)| len))
+}
+
+/// Parses a complete request, as found by `frame_len`, into the command it maps to. Keys are
+/// nodespecs, so `SET teams.payments.limit 100` updates the `limit` node in `teams.payments`, and creates it if it doesn't exist yet.
+///
+/// The request is tagged with the name of its command, which decides how the response to it is
+/// encoded, since Redis replies differently to commands that are the same to us.
+pub fn parse_request(frame: &[u8]) -> Request {
+    let args = match split_frame(frame) {
+        Ok(Some((args, _))) => args,
+        _                   => return (None, Err("invalid request")),
+    };
+
+    let args = match args.iter().map(|arg| str::from_utf8(arg)).collect::<Result<Vec<_>, _>>() {
+        Ok(args) => args,
+        Err(_)   => return (None, Err("invalid utf-8")),
+    };
+
+    match args.split_first() {
+        Some((name, args)) => {
+            let name = name.to_ascii_uppercase();
+            let cmd  = command(&name, args);
+            (Some(name), cmd)
+        },
+        None => (None, Err("missing command")),
+    }
+}
+
+/// Encodes a response to the command named by `command` as a RESP reply. Redis' replies are
+/// mimicked where they differ from the generic encoding: `GET` of a missing key is nil, and
+/// `EXISTS`, `DEL`, `EXPIRE` and `PERSIST` reply with the number of keys they found.
+///
+/// The generic encoding turns values into bulk strings (nil if they're empty), successes into
+/// `+OK`, TTLs and integers from `INCR` and friends into integers, and matches and results into
+/// arrays. Without the names of the commands in them, results of transactions are encoded the
+/// generic way, see `format_results`.
+pub fn format_response(command: Option<&str>, response: &Response) -> String {
+    match (command.unwrap_or(""), response) {
+        ("PING", Response::Value(_)) => "+PONG\r\n".to_string(),
+        ("GET", Response::Error(e)) if is_missing(e) => nil(),
+        ("EXISTS", Response::Value(_)) => integer(1),
+        ("DEL", Response::Success) |
+        ("EXPIRE", Response::Ttl(_)) | ("PERSIST", Response::Ttl(_)) => integer(1),
+        ("EXISTS", Response::Error(e)) | ("DEL", Response::Error(e)) |
+        ("EXPIRE", Response::Error(e)) | ("PERSIST", Response::Error(e)) if is_missing(e) => integer(0),
+        ("TTL", Response::Error(e)) if is_missing(e) => integer(-2),
+        ("TTL", Response::Ttl(None)) => integer(-1),
+        ("INCR", Response::Value(Value::Integer(i))) | ("INCRBY", Response::Value(Value::Integer(i))) |
+        ("DECR", Response::Value(Value::Integer(i))) | ("DECRBY", Response::Value(Value::Integer(i))) => {
+            integer(*i)
+        },
+
+        (_, Response::Success)     => "+OK\r\n".to_string(),
+        (_, Response::Queued)      => "+QUEUED\r\n".to_string(),
+        (_, Response::Error(e))    => format!("-ERR {}\r\n", e),
+        (_, Response::Value(val)) | (_, Response::Versioned(_, val)) => match val {
+            Value::Map(_) | Value::List(_) => format!("-WRONGTYPE node is a {}\r\n", val.valtype()),
+            _                              => val.payload().map_or_else(nil, |payload| bulk(&payload)),
+        },
+        (_, Response::Popped(val))     => format_response(command, &Response::Value(val)),
+        (_, Response::Ttl(None))       => nil(),
+        (_, Response::Ttl(Some(secs))) => integer(*secs as i64),
+        (_, Response::Tree(nodes)) => {
+            let keys: Vec<String> = nodes.iter().map(|(nodespec, _)| bulk(&nodespec.to_string())).collect();
+            array(&keys)
+        },
+        (_, Response::List(children, _)) => {
+            let names: Vec<String> = children.iter().map(|(name, _)| bulk(name)).collect();
+            array(&names)
+        },
+        (_, Response::Event(evt))  => bulk(&evt.to_string()),
+        // Clients find the error that aborted a transaction at the end of the results
+        (_, Response::Results(_, results)) => format_results(&[], results),
+    }
+}
+
+/// Encodes the results of a transaction as an array of the replies to the commands named by
+/// `commands`, which are the commands that were queued for it.
+pub fn format_results(commands: &[String], results: &[Outcome]) -> String {
+    let replies: Vec<String> = results.iter().enumerate()
+        .map(|(i, res)| format_response(commands.get(i).map(String::as_str), &res.as_response()))
+        .collect();
+    array(&replies)
+}
+
+fn command(name: &str, args: &[&str]) -> Result<Command, &'static str> {
+    Ok(match (name, args) {
+        ("AUTH", [secret])              => Command::Auth("default".to_string(), Secret(secret.to_string())),
+        ("AUTH", [user, secret])        => Command::Auth(user.to_string(), Secret(secret.to_string())),
+        // Every client may read the root, so this only checks that the server is there
+        ("PING", [])                    => Command::Read(NodeSpec::root()),
+        ("GET", [key])                  => Command::Read(key.parse()?),
+        ("EXISTS", [key])               => Command::Read(key.parse()?),
+        ("SET", [key, value])           => Command::Set(key.parse()?, value.to_string(), None),
+        ("SET", [key, value, ex, secs]) if ex.eq_ignore_ascii_case("EX") => {
+            Command::Set(key.parse()?, value.to_string(), Some(ttl(secs)?))
+        },
+        ("DEL", [key])                  => Command::Delete(key.parse()?, false),
+        ("INCR", [key])                 => Command::Incr(key.parse()?, None),
+        ("INCRBY", [key, amount])       => Command::Incr(key.parse()?, Some(amount.to_string())),
+        ("DECR", [key])                 => Command::Decr(key.parse()?, None),
+        ("DECRBY", [key, amount])       => Command::Decr(key.parse()?, Some(amount.to_string())),
+        ("KEYS", [pattern])             => Command::ReadMatching(pattern.parse()?),
+        ("TTL", [key])                  => Command::Ttl(key.parse()?),
+        ("EXPIRE", [key, secs])         => Command::Expire(key.parse()?, Some(ttl(secs)?)),
+        ("PERSIST", [key])              => Command::Expire(key.parse()?, None),
+        ("MULTI", [])                   => Command::Begin,
+        ("EXEC", [])                    => Command::Commit,
+        ("DISCARD", [])                 => Command::Abort,
+        _ if COMMANDS.contains(&name)   => return Err("wrong number of arguments"),
+        _                               => return Err("unknown command"),
+    })
+}
+
+const COMMANDS: &[&str] = &[
+    "AUTH", "PING", "GET", "EXISTS", "SET", "DEL", "INCR", "INCRBY", "DECR", "DECRBY", "KEYS", "TTL",
+    "EXPIRE", "PERSIST", "MULTI", "EXEC", "DISCARD",
+];
+
+fn ttl(secs: &str) -> Result<Expiry, &'static str> {
+    secs.parse().ok().filter(|&secs| secs > 0).map(Expiry::After).ok_or("invalid ttl")
+}
+
+fn is_missing(e: &str) -> bool {
+    e.starts_with("node does not exist")
+}
+
+fn nil() -> String {
+    "$-1\r\n".to_string()
+}
+
+fn integer(i: i64) -> String {
+    format!(":{}\r\n", i)
+}
+
+fn bulk(s: &str) -> String {
+    format!("${}\r\n{}\r\n", s.len(), s)
+}
+
+fn array(items: &[String]) -> String {
+    let mut s = format!("*{}\r\n", items.len());
+    for item in items {
+        s.push_str(item);
+    }
+    s
+}
+
+/// Splits the request at the start of `buf` into its arguments, returning them with the length
+/// of the request.
+fn split_frame(buf: &[u8]) -> Result<Option<(Vec<&[u8]>, usize)>, &'static str> {
+    let (line, mut pos) = match read_line(buf, 0) {
+        Some(line) => line,
+        None       => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        let args = line.split(|&b| b == b' ').filter(|arg| !arg.is_empty()).collect();
+        return Ok(Some((args, pos)));
+    }
+
+    let count    = parse_len(&line[1..])?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let (line, start) = match read_line(buf, pos) {
+            Some(line) => line,
+            None       => return Ok(None),
+        };
+        if line.first() != Some(&b'$') {
+            return Err("expected a bulk string");
+        }
+
+        let len  = parse_len(&line[1..])?;
+        let rest = &buf[start..];
+        if rest.len() < len.saturating_add(2) {
+            return Ok(None);
+        }
+        if &rest[len..len + 2] != b"\r\n" {
+            return Err("bulk string is longer than its length");
+        }
+
+        args.push(&rest[..len]);
+        pos = start + len + 2;
+    }
+
+    Ok(Some((args, pos)))
+}
+
+/// Returns the line starting at `start` without its line ending, and where the next one starts.
+fn read_line(buf: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end  = start + buf.get(start..)?.iter().position(|&b| b == b'\n')?;
+    let line = &buf[start..end];
+    if line.last() == Some(&b'\r') {
+        Some((&line[..line.len() - 1], end + 1))
+    } else {
+        Some((line, end + 1))
+    }
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize, &'static str> {
+    str::from_utf8(digits).ok().and_then(|s| s.parse().ok()).ok_or("invalid length")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<String, &'static str> {
+        parse_request(s.as_bytes()).1.map(|cmd| cmd.to_string())
+    }
+
+    #[test]
+    fn split_frames() {
+        assert_eq!(frame_len(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n"), Ok(Some(22)));
+        assert_eq!(frame_len(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n*1"), Ok(Some(22)));
+        assert_eq!(frame_len(b"*2\r\n$3\r\nGET\r\n$3\r\nfo"), Ok(None));
+        assert_eq!(frame_len(b"*2\r\n$3\r\nGET\r\n"), Ok(None));
+        assert_eq!(frame_len(b"*2\r\n$3\r\nGET\r\n$3"), Ok(None));
+        assert_eq!(frame_len(b"GET foo\r\nGET"), Ok(Some(9)));
+        assert_eq!(frame_len(b"*2\r\n:3\r\n"), Err("expected a bulk string"));
+        assert_eq!(frame_len(b"*2\r\n$2\r\nGET\r\n"), Err("bulk string is longer than its length"));
+        assert_eq!(frame_len(b"*-1\r\n"), Err("invalid length"));
+
+        // Bulk strings may contain anything, including line endings
+        let frame = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$4\r\na\r\nb\r\n";
+        assert_eq!(frame_len(frame), Ok(Some(frame.len())));
+        assert_eq!(parse(str::from_utf8(frame).unwrap()), Ok("set foo :a\r\nb".to_string()));
+    }
+
+    #[test]
+    fn parse_requests() {
+        assert_eq!(parse("*2\r\n$3\r\nget\r\n$7\r\nfoo.bar\r\n"), Ok("read foo.bar".to_string()));
+        assert_eq!(parse("GET foo\r\n"), Ok("read foo".to_string()));
+        assert_eq!(parse("set foo bar EX 10\r\n"), Ok("set -t 10 foo :bar".to_string()));
+        assert_eq!(parse("DEL foo\r\n"), Ok("delete foo".to_string()));
+        assert_eq!(parse("INCRBY foo 3\r\n"), Ok("incr foo 3".to_string()));
+        assert_eq!(parse("KEYS teams.*.limit\r\n"), Ok("read teams.*.limit".to_string()));
+        assert_eq!(parse("PERSIST foo\r\n"), Ok("ttl foo none".to_string()));
+        assert_eq!(parse("AUTH deploy hunter2\r\n"), Ok("auth deploy :hunter2".to_string()));
+        assert_eq!(parse("MULTI\r\n"), Ok("begin".to_string()));
+
+        assert_eq!(parse("GET\r\n"), Err("wrong number of arguments"));
+        assert_eq!(parse("GET foo..bar\r\n"), Err("empty name in nodespec"));
+        assert_eq!(parse("EXPIRE foo 0\r\n"), Err("invalid ttl"));
+        assert_eq!(parse("FLUSHALL\r\n"), Err("unknown command"));
+        assert_eq!(parse("\r\n"), Err("missing command"));
+        assert_eq!(parse_request(b"*1\r\n$3\r\nDEL\r\n").0, Some("DEL".to_string()));
+    }
+
+    #[test]
+    fn format_responses() {
+        let int  = Value::Integer(3);
+        let text = Value::String("a\r\nb".to_string());
+        let map  = Value::Map(Default::default());
+
+        assert_eq!(format_response(Some("GET"), &Response::Value(&int)), "$1\r\n3\r\n");
+        assert_eq!(format_response(Some("INCR"), &Response::Value(&int)), ":3\r\n");
+        assert_eq!(format_response(Some("GET"), &Response::Value(&text)), "$4\r\na\r\nb\r\n");
+        assert_eq!(format_response(Some("GET"), &Response::Value(&Value::Empty)), "$-1\r\n");
+        assert_eq!(format_response(Some("GET"), &Response::Value(&map)), "-WRONGTYPE node is a map\r\n");
+        assert_eq!(format_response(Some("GET"), &Response::Error("node does not exist")), "$-1\r\n");
+        assert_eq!(format_response(Some("SET"), &Response::Error("node does not exist")),
+                   "-ERR node does not exist\r\n");
+        assert_eq!(format_response(Some("SET"), &Response::Success), "+OK\r\n");
+        assert_eq!(format_response(Some("DEL"), &Response::Success), ":1\r\n");
+        assert_eq!(format_response(Some("DEL"), &Response::Error("node does not exist")), ":0\r\n");
+        assert_eq!(format_response(Some("EXISTS"), &Response::Value(&int)), ":1\r\n");
+        assert_eq!(format_response(Some("TTL"), &Response::Ttl(None)), ":-1\r\n");
+        assert_eq!(format_response(Some("TTL"), &Response::Ttl(Some(10))), ":10\r\n");
+        assert_eq!(format_response(Some("TTL"), &Response::Error("node does not exist")), ":-2\r\n");
+        assert_eq!(format_response(Some("PING"), &Response::Value(&map)), "+PONG\r\n");
+        assert_eq!(format_response(None, &Response::Error("unknown command")), "-ERR unknown command\r\n");
+
+        let keys = Response::Tree(vec![ ("foo.bar".parse().unwrap(), &int), ("baz".parse().unwrap(), &text) ]);
+        assert_eq!(format_response(Some("KEYS"), &keys), "*2\r\n$7\r\nfoo.bar\r\n$3\r\nbaz\r\n");
+
+        let results = Response::Results(true, vec![ Outcome::Other(Response::Success), Outcome::Value(Value::Integer(4)) ]);
+        assert_eq!(format_response(Some("EXEC"), &results), "*2\r\n+OK\r\n$1\r\n4\r\n");
+        let commands = vec![ "SET".to_string(), "INCR".to_string() ];
+        assert_eq!(format_results(&commands, &[ Outcome::Other(Response::Success), Outcome::Value(Value::Integer(4)) ]),
+                   "*2\r\n+OK\r\n:4\r\n");
+        assert_eq!(format_results(&[ "GET".to_string() ], &[ Outcome::Other(Response::Error("node does not exist")) ]),
+                   "*1\r\n$-1\r\n");
+    }
+}
@@ -1,13 +1,39 @@
 use std::convert::{From, Into};
 use std::fmt;
+use event::Event;
+use nodespec::{self, NodeSpec};
 use std::ops::Try;
-use value::Value;
+use value::{ValType, Value};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Response<'a> {
     Success,
     Value(&'a Value),
+    /// A value that was removed from the store, like a popped list item
+    Popped(Value),
+    Versioned(u64, &'a Value),
+    Tree(Vec<(NodeSpec, &'a Value)>),
+    /// Names and types of children, and the cursor for the next page if there are more
+    List(Vec<(&'a str, ValType)>, Option<&'a str>),
     Error(&'static str),
+    Event(Event),
+    Queued,
+    /// The number of seconds until a node expires, if it does
+    Ttl(Option<u64>),
+    /// Whether a transaction was committed, and the results of the commands in it
+    Results(bool, Vec<Outcome>),
+}
+
+/// A response that owns the values in it, so it can be kept while the store changes. Each
+/// protocol encodes the results of a transaction from these.
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    Value(Value),
+    Versioned(u64, Value),
+    Tree(Vec<(NodeSpec, Value)>),
+    List(Vec<(String, ValType)>, Option<String>),
+    /// Any response that doesn't refer to the store
+    Other(Response<'static>),
 }
 
 impl<'a> Response<'a> {
@@ -20,12 +46,84 @@ impl<'a> Response<'a> {
     }
 }
 
+impl Outcome {
+    /// Returns the response this is the result of.
+    pub fn as_response(&self) -> Response {
+        match self {
+            Outcome::Value(val)              => Response::Value(val),
+            Outcome::Versioned(version, val) => Response::Versioned(*version, val),
+            Outcome::Tree(nodes)             => {
+                Response::Tree(nodes.iter().map(|(nodespec, val)| (nodespec.clone(), val)).collect())
+            },
+            Outcome::List(children, next)    => {
+                let children = children.iter().map(|(name, valtype)| (name.as_str(), *valtype));
+                Response::List(children.collect(), next.as_ref().map(String::as_str))
+            },
+            Outcome::Other(response)         => response.clone(),
+        }
+    }
+}
+
+impl<'a> From<Response<'a>> for Outcome {
+    fn from(response: Response<'a>) -> Outcome {
+        match response {
+            Response::Value(val)              => Outcome::Value(val.clone()),
+            Response::Popped(val)             => Outcome::Value(val),
+            Response::Versioned(version, val) => Outcome::Versioned(version, val.clone()),
+            Response::Tree(nodes)             => {
+                Outcome::Tree(nodes.into_iter().map(|(nodespec, val)| (nodespec, val.clone())).collect())
+            },
+            Response::List(children, next)    => {
+                let children = children.into_iter().map(|(name, valtype)| (name.to_string(), valtype));
+                Outcome::List(children.collect(), next.map(str::to_string))
+            },
+            Response::Success                 => Outcome::Other(Response::Success),
+            Response::Error(err)              => Outcome::Other(Response::Error(err)),
+            Response::Event(evt)              => Outcome::Other(Response::Event(evt)),
+            Response::Queued                  => Outcome::Other(Response::Queued),
+            Response::Ttl(ttl)                => Outcome::Other(Response::Ttl(ttl)),
+            Response::Results(committed, res) => Outcome::Other(Response::Results(committed, res)),
+        }
+    }
+}
+
 impl<'a> fmt::Display for Response<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Response::Success    => write!(f, "success"),
-            Response::Value(val) => write!(f, "value {}", val),
+            Response::Success     => write!(f, "success"),
+            Response::Value(val)  => write!(f, "value {}", val),
+            Response::Popped(val) => write!(f, "value {}", val),
+            Response::Versioned(version, val) => write!(f, "version {} {}", version, val),
+            Response::Tree(nodes) => {
+                write!(f, "tree {}", nodes.len())?;
+                for (nodespec, val) in nodes {
+                    write!(f, "\n{} {}", nodespec, val)?;
+                }
+                Ok(())
+            },
+            Response::List(children, next) => {
+                write!(f, "list {}", children.len())?;
+                if let Some(next) = next {
+                    write!(f, " {}", nodespec::escape(next))?;
+                }
+                for (name, valtype) in children {
+                    write!(f, "\n{} {}", nodespec::escape(name), valtype)?;
+                }
+                Ok(())
+            },
             Response::Error(err) => write!(f, "error :{}", err),
+            Response::Event(evt) => write!(f, "event {}", evt),
+            Response::Queued     => write!(f, "queued"),
+            Response::Ttl(None)       => write!(f, "ttl none"),
+            Response::Ttl(Some(secs)) => write!(f, "ttl {}", secs),
+            Response::Results(committed, results) => {
+                let status = if *committed { "committed" } else { "aborted" };
+                write!(f, "{} {}", status, results.len())?;
+                for res in results {
+                    write!(f, "\n{}", res.as_response())?;
+                }
+                Ok(())
+            },
         }
     }
 }
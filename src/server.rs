@@ -1,13 +1,99 @@
+use acl::Acl;
+use auth::{Challenge, Secret, Users};
 use client::Client;
+use command::Command;
+use commandcodec::Limits;
+use config::Listen;
+use event::Event;
+use expiry;
+use futures::future;
+use futures::sync::mpsc::{self, Receiver, Sender};
+use http::HttpConnection;
+use nodespec::NodeSpec;
+use openssl::ssl::SslAcceptor;
+use protocol::Protocol;
+use response::{Outcome, Response};
+use snapshot;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use store::Store;
-use tokio::io;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::prelude::*;
+use tokio::timer::Interval;
 use tokio;
+use tokio_uds::UnixListener;
+use tls;
+use wal::Wal;
+
+pub type ClientId = usize;
+
+/// How often expired nodes are removed (in seconds), even if no commands come in.
+const REAP_INTERVAL: u64 = 1;
+
+/// The snapshot file that `save` and `load` use when no file is given.
+const DEFAULT_SNAPSHOT: &str = "um.snapshot";
+
+/// How many events may wait for a client that doesn't read them. Clients that fall further
+/// behind are disconnected, so they can't make the server buffer events without bound.
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// How many of the latest events are kept for clients that resume watching.
+const EVENT_HISTORY: usize = 1024;
+
+/// An event, with its sequence number. Every event gets the next number, starting at 1 when the
+/// server starts, so numbers are only meaningful along with the server's boot id.
+pub type Sequenced = (u64, Event);
+
+/// The server's side of a connected client.
+struct Connection {
+    events:  Sender<Sequenced>,
+    watches: Vec<NodeSpec>,
+
+    /// Commands queued since `begin`, if a transaction is in progress
+    transaction: Option<Vec<Command>>,
+
+    /// The user the client authenticated as
+    user: Option<String>,
+}
 
 pub struct Server {
     pub store: Store,
+    wal: Option<Wal>,
+    /// The directory clients can save snapshots to and load them from
+    snapshot_dir: PathBuf,
+    connections: HashMap<ClientId, Connection>,
+    next_client_id: ClientId,
+
+    /// The clients that created the ephemeral nodes
+    owners: HashMap<NodeSpec, ClientId>,
+
+    /// The users clients have to authenticate as, if authentication is required
+    users: Option<Users>,
+
+    /// What each user may do, if access is restricted
+    acl: Option<Acl>,
+
+    /// Sets up TLS for clients on TLS listeners
+    tls: Option<SslAcceptor>,
+
+    /// How much each client may make the server buffer
+    limits: Limits,
+
+    /// Tells this run of the server apart from earlier ones, whose events had the same numbers
+    boot: u64,
+
+    /// The sequence number of the latest event
+    sequence: u64,
+
+    /// The latest events
+    history: VecDeque<Sequenced>,
 }
 
 impl Server {
@@ -15,33 +101,589 @@ impl Server {
         let store = Store::new();
 
         Server {
-            store
+            store,
+            wal: None,
+            snapshot_dir: PathBuf::from("."),
+            connections: HashMap::new(),
+            next_client_id: 0,
+            owners: HashMap::new(),
+            users: None,
+            acl: None,
+            tls: None,
+            limits: Limits::default(),
+            boot: boot_id(),
+            sequence: 0,
+            history: VecDeque::new(),
         }
     }
 
-    pub fn run(self) {
-        // Bind the server's socket
-        let addr = "127.0.0.1:3535".parse().unwrap();
-        let tcp = TcpListener::bind(&addr).unwrap();
+    /// Creates a server that keeps its store in the log at `path`, restoring what was in it.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut server = Server::new();
+        server.wal = Some(Wal::open(path, &mut server.store)?);
+
+        // Nodes may have expired while the server wasn't running, and the clients that owned
+        // ephemeral nodes are gone
+        server.reap();
+        let ephemeral = server.store.ephemeral_nodes();
+        server.remove_ephemeral(ephemeral);
+        Ok(server)
+    }
 
+    /// Listens on all of the given addresses and serves clients until the process is killed.
+    /// Unix domain sockets get the permissions in `socket_mode`, if any.
+    pub fn run(self, listen: &[Listen], socket_mode: Option<u32>) -> io::Result<()> {
+        let tls   = self.tls.clone();
         let state = Arc::new(Mutex::new(self));
 
-        // Iterate incoming connections
-        let server = tcp.incoming().for_each(move |tcp| {
-            Server::handle_connection(tcp, state.clone());
+        // Bind all of the server's sockets before starting, so a bad address is reported
+        // instead of leaving the server running on only some of them
+        let mut listeners: Vec<Box<Future<Item = (), Error = ()> + Send>> = Vec::new();
+        for addr in listen {
+            let state = state.clone();
+            let listener: Box<Future<Item = (), Error = ()> + Send> = match addr {
+                Listen::Tcp(tcp_addr) => {
+                    let tcp = TcpListener::bind(tcp_addr).map_err(|e| bind_error(addr, e))?;
+                    Box::new(tcp.incoming().for_each(move |socket| {
+                        Server::handle_connection(socket, state.clone(), Protocol::Line);
+                        Ok(())
+                    })
+                    .map_err(|err| {
+                        println!("server error {:?}", err);
+                    }))
+                },
+                Listen::Tls(tcp_addr) => {
+                    let acceptor = tls.clone().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::Other, format!("can't listen on {}: TLS is not set up", addr))
+                    })?;
+                    let tcp = TcpListener::bind(tcp_addr).map_err(|e| bind_error(addr, e))?;
+                    Box::new(tcp.incoming().for_each(move |socket| {
+                        let state = state.clone();
+                        tokio::spawn(tls::accept(&acceptor, socket)
+                            .map(move |stream| Server::handle_connection(stream, state, Protocol::Line))
+                            .map_err(|e| println!("TLS handshake failed: {}", e)));
+                        Ok(())
+                    })
+                    .map_err(|err| {
+                        println!("server error {:?}", err);
+                    }))
+                },
+                Listen::Resp(tcp_addr) => {
+                    let tcp = TcpListener::bind(tcp_addr).map_err(|e| bind_error(addr, e))?;
+                    Box::new(tcp.incoming().for_each(move |socket| {
+                        Server::handle_connection(socket, state.clone(), Protocol::Resp);
+                        Ok(())
+                    })
+                    .map_err(|err| {
+                        println!("server error {:?}", err);
+                    }))
+                },
+                Listen::Http(tcp_addr) => {
+                    let tcp = TcpListener::bind(tcp_addr).map_err(|e| bind_error(addr, e))?;
+                    Box::new(tcp.incoming().for_each(move |socket| {
+                        tokio::spawn(HttpConnection::new(socket, state.clone())
+                            .map_err(|e| println!("error: {:#?}", e)));
+                        Ok(())
+                    })
+                    .map_err(|err| {
+                        println!("server error {:?}", err);
+                    }))
+                },
+                Listen::Unix(path) => {
+                    let uds = Self::bind_unix(path, socket_mode).map_err(|e| bind_error(addr, e))?;
+                    Box::new(uds.incoming().for_each(move |socket| {
+                        Server::handle_connection(socket, state.clone(), Protocol::Line);
+                        Ok(())
+                    })
+                    .map_err(|err| {
+                        println!("server error {:?}", err);
+                    }))
+                },
+            };
+
+            println!("listening on {}", addr);
+            listeners.push(listener);
+        }
 
+        let interval = Duration::from_secs(REAP_INTERVAL);
+        let reaper   = Interval::new(Instant::now() + interval, interval).for_each(move |_| {
+            let mut server = state.lock().unwrap();
+            server.compact_log();
+            server.reap();
+            server.notify_watchers();
             Ok(())
         })
         .map_err(|err| {
-            println!("server error {:?}", err);
+            println!("reaper error {:?}", err);
+        });
+
+        // Start the runtime and spin up the listeners
+        tokio::run(future::lazy(move || {
+            for listener in listeners {
+                tokio::spawn(listener);
+            }
+            tokio::spawn(reaper);
+            Ok(())
+        }));
+
+        Ok(())
+    }
+
+    fn bind_unix(path: &Path, socket_mode: Option<u32>) -> io::Result<UnixListener> {
+        // A socket left behind by an earlier run would make binding fail, but one that another
+        // server still accepts connections on has to stay
+        if fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+            match UnixStream::connect(path) {
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on it")),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+                Err(_) => {},
+            }
+        }
+        if fs::symlink_metadata(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+        }
+
+        // The socket is bound in a directory that only we can enter, and only moved into place
+        // once it has its permissions, so nobody can connect to it before that
+        let mut dir = path.as_os_str().to_owned();
+        dir.push(format!(".{}.tmp", process::id()));
+        let dir = PathBuf::from(dir);
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let tmp = dir.join("socket");
+        let uds = UnixListener::bind(&tmp).and_then(|uds| {
+            if let Some(mode) = socket_mode {
+                fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+            }
+            fs::rename(&tmp, path)?;
+            Ok(uds)
+        });
+
+        let _ = fs::remove_file(&tmp);
+        let _ = fs::remove_dir(&dir);
+        uds
+    }
+
+    /// Makes clients authenticate as one of `users` before they can use any other command.
+    pub fn require_auth(&mut self, users: Users) {
+        self.users = Some(users);
+    }
+
+    /// Only lets clients execute the commands that `acl` permits for the user they
+    /// authenticated as.
+    pub fn restrict_access(&mut self, acl: Acl) {
+        self.acl = Some(acl);
+    }
+
+    /// Lets the server listen for TLS connections, which are set up by `acceptor`.
+    pub fn enable_tls(&mut self, acceptor: SslAcceptor) {
+        self.tls = Some(acceptor);
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_snapshot_dir(&mut self, dir: PathBuf) {
+        self.snapshot_dir = dir;
+    }
+
+    /// Returns the path of the snapshot file a client named. Clients can only use files in the
+    /// snapshot directory, so they can't overwrite anything else the server can write to.
+    fn snapshot_file(&self, name: Option<String>) -> Result<PathBuf, &'static str> {
+        let name = name.unwrap_or_else(|| DEFAULT_SNAPSHOT.to_string());
+
+        let mut components = Path::new(&name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(file)), None) => Ok(self.snapshot_dir.join(file)),
+            _ => Err("snapshot must be a file name without a directory"),
+        }
+    }
+
+    /// Returns true if there's no log, or nothing has been logged yet.
+    pub fn log_is_empty(&self) -> bool {
+        self.wal.as_ref().map_or(true, Wal::is_empty)
+    }
+
+    /// Replaces the store with the tree in the snapshot file at `path`.
+    pub fn load_snapshot(&mut self, path: &Path) -> io::Result<()> {
+        let root = snapshot::load(path)?;
+        self.store.replace_root(root).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // The nodes clients created are gone, even if the snapshot has nodes at the same paths
+        self.owners.clear();
+
+        // Nothing in the log before this point matters anymore
+        if let Some(wal) = self.wal.as_mut() {
+            wal.compact(&self.store)?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers a new client. Events for the nodes it watches can be received from the
+    /// returned stream.
+    pub fn connect(&mut self) -> (ClientId, Receiver<Sequenced>) {
+        let (tx, rx) = mpsc::channel(MAX_QUEUED_EVENTS);
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+
+        self.connections.insert(id, Connection {
+            events:  tx,
+            watches: Vec::new(),
+            transaction: None,
+            user: None,
         });
 
-        // Start the runtime and spin up the server
-        tokio::run(server);
+        (id, rx)
+    }
+
+    /// Forgets about a client, and removes the ephemeral nodes it created.
+    pub fn disconnect(&mut self, client: ClientId) {
+        self.connections.remove(&client);
+
+        let owned: Vec<NodeSpec> = self.owners.iter()
+            .filter(|(_, &owner)| owner == client)
+            .map(|(nodespec, _)| nodespec.clone())
+            .collect();
+        for nodespec in &owned {
+            self.owners.remove(nodespec);
+        }
+
+        self.remove_ephemeral(owned);
+        self.notify_watchers();
+    }
+
+    /// Removes the given nodes if they're (still) ephemeral, logging it as a single transaction.
+    fn remove_ephemeral(&mut self, mut nodespecs: Vec<NodeSpec>) {
+        // Parents sort before their children, which are removed along with them
+        nodespecs.sort();
+
+        let mut records = Vec::new();
+        for nodespec in nodespecs {
+            if !self.store.get_node(&nodespec).map(|n| n.is_ephemeral()).unwrap_or(false) {
+                continue;
+            }
+
+            let cmd = Command::Delete(nodespec, true);
+            records.push(cmd.to_string());
+            self.store.execute(cmd);
+        }
+
+        if !records.is_empty() {
+            // The error has been reported already, and there's no client to send it to
+            let _ = Self::log(&mut self.wal, &records);
+        }
+    }
+
+    /// Starts authenticating `client` as `user`. Until it's finished with `finish_auth`, the
+    /// client isn't authenticated at all, not even as who it was before.
+    pub fn start_auth(&mut self, client: ClientId, user: &str) -> Result<Challenge, &'static str> {
+        let users = self.users.as_mut().ok_or("authentication is not enabled")?;
+        let conn  = self.connections.get_mut(&client).ok_or("client is not connected")?;
+        conn.user = None;
+        users.challenge(user, Instant::now()).map_err(|e| {
+            println!("client {} failed to authenticate as {}: {}", client, user, e);
+            e
+        })
+    }
+
+    /// Authenticates `client` as `user` right away if `secret` was remembered for them.
+    fn auth_remembered(&mut self, client: ClientId, user: &str, secret: &Secret) -> bool {
+        let remembered = self.users.as_ref().map_or(false, |u| u.is_remembered(user, secret));
+        match self.connections.get_mut(&client) {
+            Some(conn) if remembered => conn.user = Some(user.to_string()),
+            _                        => return false,
+        }
+        true
+    }
+
+    pub fn finish_auth(&mut self, client: ClientId, user: String, valid: bool) -> Response<'static> {
+        let users = self.users.as_mut().ok_or("authentication is not enabled")?;
+        let conn  = self.connections.get_mut(&client).ok_or("client is not connected")?;
+        if let Err(e) = users.finish(&user, valid) {
+            println!("client {} failed to authenticate as {}: {}", client, user, e);
+            return Response::Error(e);
+        }
+        println!("client {} authenticated as {}", client, user);
+        conn.user = Some(user);
+        Response::Success
+    }
+
+    pub fn execute(&mut self, client: ClientId, cmd: Command) -> Response {
+        self.compact_log();
+        self.reap();
+
+        let in_transaction = self.connections.get(&client).map_or(false, |c| c.transaction.is_some());
+        let authenticated  = self.users.is_none() ||
+                             self.connections.get(&client).map_or(false, |c| c.user.is_some());
+
+        match cmd {
+            Command::Auth(user, secret) => {
+                let valid = self.start_auth(client, &user)?.verify(&secret);
+                self.finish_auth(client, user, valid)
+            },
+            // Clients switch protocols themselves
+            Command::Proto(_) => Response::Success,
+            _ if !authenticated => Response::Error("not authenticated"),
+            ref cmd if !self.permits(client, cmd) => Response::Error("permission denied"),
+            ref cmd if self.below_foreign_ephemeral(client, cmd) => {
+                Response::Error("can't create nodes below another client's ephemeral node")
+            },
+            Command::Begin => {
+                let conn = self.connections.get_mut(&client).ok_or("client is not connected")?;
+                if conn.transaction.is_some() {
+                    return Response::Error("transaction already in progress");
+                }
+                conn.transaction = Some(Vec::new());
+                Response::Success
+            },
+            Command::Abort => {
+                let conn = self.connections.get_mut(&client).ok_or("client is not connected")?;
+                conn.transaction.take().ok_or("no transaction in progress")?;
+                Response::Success
+            },
+            Command::Commit => {
+                let conn = self.connections.get_mut(&client).ok_or("client is not connected")?;
+                let cmds = conn.transaction.take().ok_or("no transaction in progress")?;
+                self.execute_transaction(client, cmds)
+            },
+            cmd if in_transaction => {
+                if !cmd.is_transactional() {
+                    return Response::Error("command can't be part of a transaction");
+                }
+                let conn = self.connections.get_mut(&client).ok_or("client is not connected")?;
+                conn.transaction.as_mut().unwrap().push(cmd);
+                Response::Queued
+            },
+            Command::Watch(nodespec) => {
+                let conn = self.connections.get_mut(&client).ok_or("client is not connected")?;
+                if conn.watches.contains(&nodespec) {
+                    return Response::Error("already watching this node");
+                }
+                conn.watches.push(nodespec);
+                Response::Success
+            },
+            Command::Unwatch(nodespec) => {
+                let conn = self.connections.get_mut(&client).ok_or("client is not connected")?;
+                let pos  = conn.watches.iter().position(|w| w == &nodespec)
+                               .ok_or("not watching this node")?;
+                conn.watches.remove(pos);
+                Response::Success
+            },
+            Command::Save(path) => {
+                let path = self.snapshot_file(path)?;
+                if let Err(e) = snapshot::save(self.store.root(), &path) {
+                    println!("error saving snapshot to {}: {}", path.display(), e);
+                    return Response::Error("could not save snapshot");
+                }
+                Response::Success
+            },
+            Command::Load(path) => {
+                let path = self.snapshot_file(path)?;
+                if let Err(e) = self.load_snapshot(&path) {
+                    println!("error loading snapshot from {}: {}", path.display(), e);
+                    return Response::Error("could not load snapshot");
+                }
+                Response::Success
+            },
+            mut cmd => {
+                if !cmd.is_mutating() {
+                    return self.store.execute(cmd);
+                }
+
+                cmd.resolve_expiry(self.store.now());
+                let record    = cmd.to_string();
+                let ephemeral = cmd.ephemeral_node();
+                let response  = self.store.execute(cmd);
+                if !response.is_err() {
+                    if let Some(nodespec) = ephemeral {
+                        self.owners.insert(nodespec, client);
+                    }
+
+                    // The change is kept even if it can't be logged, since the client would
+                    // apply it twice by retrying. The log is rewritten from the store instead.
+                    let _ = Self::log(&mut self.wal, &[ record ]);
+                }
+                response
+            },
+        }
+    }
+
+    /// Returns true if the access control list, if any, lets the client execute `cmd`.
+    fn permits(&self, client: ClientId, cmd: &Command) -> bool {
+        let user = self.connections.get(&client).and_then(|c| c.user.as_ref()).map(String::as_str);
+        self.acl.as_ref().map_or(true, |acl| acl.permits(user, cmd))
+    }
+
+    /// Returns true if `cmd` adds a node below an ephemeral node that another client created.
+    /// That node would be removed along with the ephemeral one when the other client
+    /// disconnects. Owners whose node has since been deleted or replaced don't count.
+    fn below_foreign_ephemeral(&self, client: ClientId, cmd: &Command) -> bool {
+        cmd.parent_node().map_or(false, |parent| {
+            self.owners.iter().any(|(nodespec, &owner)| {
+                owner != client
+                    && parent.starts_with(nodespec)
+                    && self.store.node(nodespec).map_or(false, |n| n.is_ephemeral())
+            })
+        })
+    }
+
+    /// Executes all commands, or none of them if any of them fails. The response holds the
+    /// result of every command that was executed.
+    fn execute_transaction(&mut self, client: ClientId, mut cmds: Vec<Command>) -> Response {
+        for cmd in &mut cmds {
+            cmd.resolve_expiry(self.store.now());
+        }
+
+        let ephemeral: Vec<NodeSpec> = cmds.iter().filter_map(Command::ephemeral_node).collect();
+
+        let records: Vec<String> = cmds.iter()
+            .filter(|cmd| cmd.is_mutating())
+            .map(|cmd| cmd.to_string())
+            .collect();
+
+        let mut results = Vec::with_capacity(cmds.len());
+
+        self.store.begin();
+        for cmd in cmds {
+            // Another client may have created an ephemeral node since the command was queued
+            let res = if self.below_foreign_ephemeral(client, &cmd) {
+                Response::Error("can't create nodes below another client's ephemeral node")
+            } else {
+                self.store.execute(cmd)
+            };
+            let failed = res.is_err();
+            results.push(Outcome::from(res));
+
+            if failed {
+                self.store.rollback();
+                return Response::Results(false, results);
+            }
+        }
+
+        // Unlike single commands, transactions can still be undone if they can't be logged
+        if !records.is_empty() {
+            if let Err(e) = Self::log(&mut self.wal, &records) {
+                self.store.rollback();
+                return Response::Error(e);
+            }
+        }
+        self.store.commit();
+
+        for nodespec in ephemeral {
+            self.owners.insert(nodespec, client);
+        }
+
+        Response::Results(true, results)
+    }
+
+    /// Appends commands that changed the store to the log, if there is one. Multiple commands
+    /// are logged as a single transaction.
+    ///
+    /// This doesn't borrow the rest of the server, so it can be called while a response still
+    /// refers to the store.
+    fn log(wal: &mut Option<Wal>, records: &[String]) -> Result<(), &'static str> {
+        if let Some(wal) = wal.as_mut() {
+            let res = match records {
+                [ record ] => wal.append(record),
+                _          => wal.append_transaction(records),
+            };
+
+            if let Err(e) = res {
+                println!("error writing to log, it's rewritten from the store later: {}", e);
+                return Err("change could not be written to the log");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the nodes that have expired, and logs their removal.
+    pub fn reap(&mut self) {
+        let records: Vec<String> = self.store.reap(expiry::now()).into_iter()
+            .map(|nodespec| Command::Delete(nodespec, true).to_string())
+            .collect();
+
+        if !records.is_empty() {
+            // The error has been reported already, and there's no client to send it to
+            let _ = Self::log(&mut self.wal, &records);
+        }
+    }
+
+    /// Compacts the log if it has grown large enough.
+    fn compact_log(&mut self) {
+        if let Some(wal) = self.wal.as_mut() {
+            if wal.needs_compaction() {
+                if let Err(e) = wal.compact(&self.store) {
+                    println!("error compacting log: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Sends the events for all changes made to the store since the last call to the clients
+    /// watching the affected nodes. Clients that have too many events waiting already are
+    /// disconnected instead.
+    pub fn notify_watchers(&mut self) {
+        let mut lagging = Vec::new();
+        for event in self.store.take_events() {
+            self.sequence += 1;
+            for (&id, conn) in self.connections.iter_mut() {
+                if conn.watches.iter().any(|w| event.affects(w)) {
+                    // This also fails if the client is already gone
+                    let full = conn.events.try_send((self.sequence, event.clone())).err().map_or(false, |e| e.is_full());
+                    if full && !lagging.contains(&id) {
+                        lagging.push(id);
+                    }
+                }
+            }
+
+            if self.history.len() == EVENT_HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back((self.sequence, event));
+        }
+
+        for id in lagging {
+            println!("client {} fell too far behind on events", id);
+            self.disconnect(id);
+        }
+    }
+
+    /// Returns the id of this run of the server.
+    pub fn boot(&self) -> u64 {
+        self.boot
+    }
+
+    /// Returns the sequence number of the latest event.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Returns the events after the one numbered `since` that affect `watch`, for a client that
+    /// resumes watching it. Returns `None` if some of those events have been forgotten, or if
+    /// `since` is from another run of the server than `boot`.
+    pub fn events_since(&self, boot: u64, since: u64, watch: &NodeSpec) -> Option<Vec<Sequenced>> {
+        let oldest = self.history.front().map_or(self.sequence + 1, |&(seq, _)| seq);
+        if boot != self.boot || since > self.sequence || since + 1 < oldest {
+            return None;
+        }
+
+        Some(self.history.iter()
+            .filter(|(seq, event)| *seq > since && event.affects(watch))
+            .cloned()
+            .collect())
     }
 
-    pub fn handle_connection(socket: TcpStream, state: Arc<Mutex<Self>>) {
-        let client = Client::new(socket, state)
+    pub fn handle_connection<T>(socket: T, state: Arc<Mutex<Self>>, protocol: Protocol) where
+        T: AsyncRead + AsyncWrite + Send + 'static
+    {
+        let client = Client::new(socket, state, protocol)
             .map_err(|e| println!("error: {:#?}", e));
 
         // Spawn the future as a concurrent task
@@ -49,13 +691,541 @@ impl Server {
     }
 }
 
+/// Authenticates `client` like `Command::Auth`, but checks the secret without holding the lock
+/// on the server, so other clients aren't held up meanwhile.
+pub fn authenticate(state: &Mutex<Server>, client: ClientId, user: String, secret: &Secret) -> Response<'static> {
+    let challenge = state.lock().unwrap().start_auth(client, &user);
+    let valid     = challenge?.verify(secret);
+    state.lock().unwrap().finish_auth(client, user, valid)
+}
+
+/// Like `authenticate`, but remembers secrets that were right, so checking them again is quick.
+/// This is for clients that send their secret with every request, like HTTP clients. A
+/// remembered secret isn't subject to the limit on failed attempts, so others can't lock these
+/// clients out by failing on purpose. Clients whose secret isn't remembered yet still can be.
+pub fn authenticate_cached(state: &Mutex<Server>, client: ClientId, user: String, secret: &Secret) -> Response<'static> {
+    if state.lock().unwrap().auth_remembered(client, &user, secret) {
+        return Response::Success;
+    }
+
+    let response = authenticate(state, client, user.clone(), secret);
+    if !response.is_err() {
+        if let Some(ref mut users) = state.lock().unwrap().users {
+            users.remember(&user, secret);
+        }
+    }
+    response
+}
+
+/// Returns the time the server started in nanoseconds since the unix epoch, which is unique
+/// enough to identify a run of it.
+fn boot_id() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())
+}
+
+fn bind_error(addr: &Listen, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("can't listen on {}: {}", addr, e))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ::std::io::prelude::*;
-    use ::std::net::TcpStream;
+    use auth;
+    use duplex::{duplex, duplex_with_capacity, NoNotify};
+    use futures::executor;
+    use value::Value;
+
+    #[test]
+    fn commands_over_transport() {
+        let state = Arc::new(Mutex::new(Server::new()));
+        let (socket, mut remote) = duplex();
+        let mut client = executor::spawn(Client::new(socket, state.clone(), Protocol::Line));
+
+        remote.write_all(b"create . foo integer\nupdate foo 5\r\n@1 read foo\nwatch foo\n@2 frobnicate\n").unwrap();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_not_ready());
+
+        // Events are sent after the responses
+        remote.write_all(b"incr foo\n").unwrap();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_not_ready());
+        remote.close();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_ready());
+        drop(client);
+
+        let mut out = String::new();
+        remote.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "success\nsuccess\n@1 value integer 5\nsuccess\n@2 error :unknown command\nvalue integer 6\nevent update foo integer 6\n");
+        assert!(state.lock().unwrap().connections.is_empty());
+    }
+
+    #[test]
+    fn json_protocol() {
+        let state = Arc::new(Mutex::new(Server::new()));
+        let (socket, mut remote) = duplex();
+        let mut client = executor::spawn(Client::new(socket, state.clone(), Protocol::Line));
+
+        remote.write_all(b"create . foo integer\nproto json\n").unwrap();
+        remote.write_all(br#"{"command": "update", "nodespec": ["foo"], "value": 5, "tag": 1}"#).unwrap();
+        remote.write_all(b"\nread foo\n").unwrap();
+        remote.write_all(br#"{"command": "begin"}"#).unwrap();
+        remote.write_all(b"\n").unwrap();
+        remote.write_all(br#"{"command": "incr", "nodespec": ["foo"], "amount": 2}"#).unwrap();
+        remote.write_all(b"\n").unwrap();
+        remote.write_all(br#"{"command": "commit"}"#).unwrap();
+        remote.write_all(b"\n").unwrap();
+        remote.write_all(br#"{"command": "proto", "protocol": "line"}"#).unwrap();
+        remote.write_all(b"\nread foo\n").unwrap();
+        remote.close();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_ready());
+        drop(client);
+
+        let mut out = String::new();
+        remote.read_to_string(&mut out).unwrap();
+        assert_eq!(out.lines().collect::<Vec<_>>(), vec![
+            "success",
+            "success",
+            r#"{"status":"success","tag":1}"#,
+            r#"{"error":"invalid json","status":"error"}"#,
+            r#"{"status":"success"}"#,
+            r#"{"status":"queued"}"#,
+            r#"{"results":[{"status":"value","type":"integer","value":7}],"status":"committed"}"#,
+            r#"{"status":"success"}"#,
+            "value integer 7",
+        ]);
+    }
+
+    #[test]
+    fn resp_protocol() {
+        let state = Arc::new(Mutex::new(Server::new()));
+        {
+            let mut server = state.lock().unwrap();
+            let (admin, _) = server.connect();
+            server.execute(admin, "create . counter integer".parse().unwrap());
+        }
+        let (socket, mut remote) = duplex();
+        let mut client = executor::spawn(Client::new(socket, state.clone(), Protocol::Resp));
+
+        remote.write_all(b"*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n$7\r\ncounter\r\n$1\r\n5\r\n").unwrap();
+        remote.write_all(b"INCRBY counter 2\r\nGET counter\r\nGET missing\r\nEXISTS missing\r\n").unwrap();
+        remote.write_all(b"MULTI\r\nINCR counter\r\nSET name hello\r\nEXEC\r\nGET name\r\n").unwrap();
+        remote.write_all(b"DEL counter\r\nFLUSHALL\r\n").unwrap();
+        remote.close();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_ready());
+        drop(client);
+
+        let mut out = String::new();
+        remote.read_to_string(&mut out).unwrap();
+        assert_eq!(out, [
+            "+PONG\r\n",
+            "+OK\r\n",
+            ":7\r\n",
+            "$1\r\n7\r\n",
+            "$-1\r\n",
+            ":0\r\n",
+            "+OK\r\n",
+            "+QUEUED\r\n",
+            "+QUEUED\r\n",
+            "*2\r\n:8\r\n+OK\r\n",
+            "$5\r\nhello\r\n",
+            ":1\r\n",
+            "-ERR unknown command\r\n",
+        ].concat());
+    }
+
+    #[test]
+    fn broken_resp_requests_close_the_connection() {
+        let state = Arc::new(Mutex::new(Server::new()));
+        let (socket, mut remote) = duplex();
+        let mut client = executor::spawn(Client::new(socket, state.clone(), Protocol::Resp));
+
+        remote.write_all(b"*2\r\n$3\r\nGET\r\n:3\r\n").unwrap();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).is_err());
+    }
 
     #[test]
-    fn commands_over_tcp() {
+    fn backpressure() {
+        let state = Arc::new(Mutex::new(Server::new()));
+        state.lock().unwrap().set_limits(Limits { max_line: 64, max_pending: 32 });
+        let (socket, mut remote) = duplex_with_capacity(128);
+        let mut client = executor::spawn(Client::new(socket, state.clone(), Protocol::Line));
+        let read_foo   = || state.lock().unwrap().store.execute("read foo".parse().unwrap()).to_string();
+
+        remote.write_all(b"create . foo integer\n").unwrap();
+        for _ in 0..11 {
+            remote.write_all(b"incr foo\n").unwrap();
+        }
+
+        // The output of the first 10 commands fills up the transport and the write buffer
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_not_ready());
+        assert_eq!(read_foo(), "value integer 10");
+
+        let mut buf = [0; 1024];
+        assert_eq!(remote.read(&mut buf).unwrap(), 128);
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_not_ready());
+        assert_eq!(read_foo(), "value integer 11");
+
+        remote.close();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_ready());
+        drop(client);
+
+        let mut out = String::from_utf8(buf[..128].to_vec()).unwrap();
+        remote.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("success\nvalue integer 1\n"));
+        assert!(out.ends_with("value integer 10\nvalue integer 11\n"));
+    }
+
+    #[test]
+    fn events_beyond_the_pending_limit() {
+        let state = Arc::new(Mutex::new(Server::new()));
+        state.lock().unwrap().set_limits(Limits { max_line: 64, max_pending: 32 });
+        let (socket, mut remote) = duplex();
+        let mut client = executor::spawn(Client::new(socket, state.clone(), Protocol::Line));
+
+        remote.write_all(b"create . foo integer\nwatch foo\n").unwrap();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_not_ready());
+
+        // The events don't fit in the write buffer, but the transport takes them all at once
+        {
+            let mut server = state.lock().unwrap();
+            let (other, _) = server.connect();
+            for _ in 0..10 {
+                assert!(!server.execute(other, "incr foo".parse().unwrap()).is_err());
+            }
+            server.notify_watchers();
+        }
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_not_ready());
+
+        remote.close();
+        assert!(client.poll_future_notify(&Arc::new(NoNotify), 0).unwrap().is_ready());
+        drop(client);
+
+        let mut out = String::new();
+        remote.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("event update foo integer 9\nevent update foo integer 10\n"));
+    }
+
+    #[test]
+    fn disconnect_lagging_watchers() {
+        let mut server = Server::new();
+        let (a, events) = server.connect();
+        let (b, _)      = server.connect();
+        assert!(!server.execute(a, "watch foo".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create . foo integer".parse().unwrap()).is_err());
+
+        // `a` never reads its events
+        for _ in 0..MAX_QUEUED_EVENTS + 1 {
+            assert!(!server.execute(b, "incr foo".parse().unwrap()).is_err());
+            server.notify_watchers();
+        }
+
+        assert!(!server.connections.contains_key(&a));
+        assert!(server.connections.contains_key(&b));
+        assert!(events.wait().count() <= MAX_QUEUED_EVENTS + 1);
+    }
+
+    #[test]
+    fn watch_notifications() {
+        let mut server = Server::new();
+        let (a, events_a) = server.connect();
+        let (b, events_b) = server.connect();
+
+        assert!(!server.execute(a, "watch foo".parse().unwrap()).is_err());
+        assert!(server.execute(a, "watch foo".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "watch bar".parse().unwrap()).is_err());
+
+        assert!(!server.execute(b, "create . foo map".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create foo baz integer".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create . qux integer".parse().unwrap()).is_err());
+        server.notify_watchers();
+
+        assert!(!server.execute(a, "unwatch foo".parse().unwrap()).is_err());
+        assert!(server.execute(a, "unwatch foo".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "update foo.baz 3".parse().unwrap()).is_err());
+        server.notify_watchers();
+
+        server.disconnect(a);
+        server.disconnect(b);
+
+        let events: Vec<String> = events_a.wait().map(|e| e.unwrap().1.to_string()).collect();
+        assert_eq!(events, vec![ "create foo map 0", "create foo.baz integer 0" ]);
+        assert_eq!(events_b.wait().count(), 0);
+    }
+
+    #[test]
+    fn transactions() {
+        let mut server = Server::new();
+        let (a, events) = server.connect();
+        let (b, _)      = server.connect();
+
+        assert!(server.execute(a, "commit".parse().unwrap()).is_err());
+        assert!(server.execute(a, "abort".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "watch net".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "begin".parse().unwrap()).is_err());
+        assert!(server.execute(a, "begin".parse().unwrap()).is_err());
+        assert!(server.execute(a, "watch foo".parse().unwrap()).is_err());
+
+        // Nothing is applied until the transaction is committed
+        for cmd in &[ "create . net map", "create net host string", "update net.host :example.com" ] {
+            match server.execute(a, cmd.parse().unwrap()) {
+                Response::Queued => (),
+                res              => panic!("{} wasn't queued: {:?}", cmd, res),
+            }
+        }
+        assert!(server.execute(b, "read net".parse().unwrap()).is_err());
+
+        let res = server.execute(a, "commit".parse().unwrap()).to_string();
+        assert_eq!(res, "committed 3\nsuccess\nsuccess\nsuccess");
+        assert!(!server.execute(b, "read net.host".parse().unwrap()).is_err());
+        server.notify_watchers();
+
+        // A failing command rolls back the whole transaction
+        assert!(!server.execute(a, "begin".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "update net.host :example.org".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "read net.host".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "create net port integer".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "update net.port :not a number".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "create net timeout integer".parse().unwrap()).is_err());
+
+        let res = server.execute(a, "commit".parse().unwrap()).to_string();
+        assert_eq!(res, "aborted 4\nsuccess\nvalue string :example.org\nsuccess\nerror :invalid integer");
+        assert!(server.execute(b, "read net.port".parse().unwrap()).is_err());
+        match server.execute(b, "read net.host".parse().unwrap()) {
+            Response::Value(&Value::String(ref s)) => assert_eq!(s, "example.com"),
+            res                                    => panic!("expected a string value but got {:?}", res),
+        }
+        server.notify_watchers();
+
+        // Aborted transactions are simply dropped
+        assert!(!server.execute(a, "begin".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "delete -r net".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "abort".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "read net.host".parse().unwrap()).is_err());
+
+        server.disconnect(a);
+        let events: Vec<String> = events.wait().map(|e| e.unwrap().1.to_string()).collect();
+        assert_eq!(events, vec![
+            "create net map 0",
+            "create net.host string :",
+            "update net.host string :example.com",
+        ]);
+    }
+
+    #[test]
+    fn save_and_load_snapshots() {
+        let name = format!("um-test-server-{}.snapshot", ::std::process::id());
+        let path = ::std::env::temp_dir().join(&name);
+        let save = format!("save :{}", name);
+        let load = format!("load :{}", name);
+
+        let mut server = Server::new();
+        server.set_snapshot_dir(::std::env::temp_dir());
+        let (client, events) = server.connect();
+        assert!(!server.execute(client, "create . foo integer".parse().unwrap()).is_err());
+        assert!(!server.execute(client, save.parse().unwrap()).is_err());
+
+        // Clients can't reach files outside of the snapshot directory
+        assert!(server.execute(client, format!("save :{}", path.display()).parse().unwrap()).is_err());
+        assert!(server.execute(client, "save :../um.snapshot".parse().unwrap()).is_err());
+        assert!(server.execute(client, "load :snapshots/um.snapshot".parse().unwrap()).is_err());
+
+        assert!(!server.execute(client, "delete foo".parse().unwrap()).is_err());
+        assert!(!server.execute(client, "create . bar integer".parse().unwrap()).is_err());
+
+        let (other, _) = server.connect();
+        assert!(!server.execute(other, "create -e . tmp map".parse().unwrap()).is_err());
+        server.store.take_events();
+
+        assert!(!server.execute(client, "watch foo".parse().unwrap()).is_err());
+        assert!(!server.execute(client, load.parse().unwrap()).is_err());
+        assert!(!server.execute(client, "read foo".parse().unwrap()).is_err());
+        assert!(server.execute(client, "read bar".parse().unwrap()).is_err());
+        assert!(server.owners.is_empty());
+        server.notify_watchers();
+        server.disconnect(client);
+
+        let events: Vec<String> = events.wait().map(|e| e.unwrap().1.to_string()).collect();
+        assert_eq!(events, vec![ "create foo integer 0" ]);
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn event_history() {
+        let mut server = Server::new();
+        let (client, _) = server.connect();
+        server.execute(client, "create . foo integer".parse().unwrap());
+        server.execute(client, "create . bar integer".parse().unwrap());
+        server.notify_watchers();
+
+        let foo = "foo".parse().unwrap();
+        let seqs = |events: Option<Vec<Sequenced>>| events.map(|e| e.iter().map(|&(seq, _)| seq).collect::<Vec<_>>());
+        let boot = server.boot();
+        assert_eq!(seqs(server.events_since(boot, 0, &foo)), Some(vec![ 1 ]));
+        assert_eq!(seqs(server.events_since(boot, 1, &foo)), Some(vec![]));
+        assert_eq!(seqs(server.events_since(boot, 3, &foo)), None);
+
+        // Numbers from before a restart are meaningless, even when they're known
+        assert_eq!(seqs(server.events_since(Server::new().boot(), 0, &foo)), None);
+
+        for _ in 0..EVENT_HISTORY {
+            server.execute(client, "incr foo".parse().unwrap());
+            server.notify_watchers();
+        }
+        assert_eq!(server.sequence(), EVENT_HISTORY as u64 + 2);
+        assert_eq!(seqs(server.events_since(boot, 1, &foo)), None);
+        assert_eq!(seqs(server.events_since(boot, 2, &foo)).map(|s| s.len()), Some(EVENT_HISTORY));
+    }
+
+    #[test]
+    fn authentication() {
+        let path = ::std::env::temp_dir().join(format!("um-test-server-{}.users", ::std::process::id()));
+        let line = auth::hash_line("deploy", "hunter2").unwrap();
+        ::std::fs::File::create(&path).unwrap().write_all(line.as_bytes()).unwrap();
+
+        let mut server = Server::new();
+        let (client, _) = server.connect();
+        assert!(server.execute(client, "auth deploy hunter2".parse().unwrap()).is_err());
+
+        server.require_auth(Users::load(&path).unwrap());
+        ::std::fs::remove_file(&path).unwrap();
+
+        // Nothing else is accepted before authenticating, not even transactions
+        for cmd in &[ "read .", "create . foo integer", "begin", "watch foo" ] {
+            match server.execute(client, cmd.parse().unwrap()) {
+                Response::Error("not authenticated") => (),
+                res                                  => panic!("{} was accepted: {:?}", cmd, res),
+            }
+        }
+
+        assert!(server.execute(client, "auth deploy hunter3".parse().unwrap()).is_err());
+        assert!(server.execute(client, "read .".parse().unwrap()).is_err());
+        assert!(!server.execute(client, "auth deploy hunter2".parse().unwrap()).is_err());
+        assert!(!server.execute(client, "create . foo integer".parse().unwrap()).is_err());
+
+        // A failed attempt undoes an earlier one, also when done without holding the lock
+        let state = Mutex::new(server);
+        assert!(authenticate(&state, client, "deploy".to_string(), &Secret("hunter3".to_string())).is_err());
+        let mut server = state.into_inner().unwrap();
+        assert!(server.execute(client, "read foo".parse().unwrap()).is_err());
+        assert!(!server.execute(client, "auth deploy hunter2".parse().unwrap()).is_err());
+        assert!(server.execute(client, "auth nobody hunter2".parse().unwrap()).is_err());
+        assert!(server.execute(client, "read foo".parse().unwrap()).is_err());
+
+        // Other clients still have to authenticate themselves
+        let (other, _) = server.connect();
+        assert!(server.execute(other, "read foo".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn access_control() {
+        let dir   = ::std::env::temp_dir();
+        let users = dir.join(format!("um-test-server-acl-{}.users", ::std::process::id()));
+        let acl   = dir.join(format!("um-test-server-{}.acl", ::std::process::id()));
+        let lines = [ auth::hash_line("admin", "a").unwrap(), auth::hash_line("payments", "p").unwrap() ];
+        ::std::fs::File::create(&users).unwrap().write_all(lines.join("\n").as_bytes()).unwrap();
+        ::std::fs::File::create(&acl).unwrap().write_all(b"\
+            admin    .               rcud\n\
+            payments teams.payments  rcud\n\
+            payments global          r\n").unwrap();
+
+        let mut server = Server::new();
+        server.require_auth(Users::load(&users).unwrap());
+        server.restrict_access(Acl::load(&acl).unwrap());
+        ::std::fs::remove_file(&users).unwrap();
+        ::std::fs::remove_file(&acl).unwrap();
+
+        let (admin, _)    = server.connect();
+        let (payments, _) = server.connect();
+        assert!(!server.execute(admin, "auth admin a".parse().unwrap()).is_err());
+        assert!(!server.execute(payments, "auth payments p".parse().unwrap()).is_err());
+
+        for cmd in &[ "create . teams map", "create teams payments map", "create . global map" ] {
+            assert!(!server.execute(admin, cmd.parse().unwrap()).is_err());
+        }
+        assert!(!server.execute(payments, "create teams.payments limit integer".parse().unwrap()).is_err());
+        assert!(!server.execute(payments, "read global".parse().unwrap()).is_err());
+
+        // Denied commands aren't even queued in transactions
+        assert!(!server.execute(payments, "begin".parse().unwrap()).is_err());
+        for cmd in &[ "create global region string", "delete -r teams", "dump ." ] {
+            match server.execute(payments, cmd.parse().unwrap()) {
+                Response::Error("permission denied") => (),
+                res                                  => panic!("{} was permitted: {:?}", cmd, res),
+            }
+        }
+        assert!(!server.execute(payments, "update teams.payments.limit 10".parse().unwrap()).is_err());
+        assert_eq!(server.execute(payments, "commit".parse().unwrap()).to_string(), "committed 1\nsuccess");
+    }
+
+    #[test]
+    fn ephemeral_nodes() {
+        let path = ::std::env::temp_dir().join(format!("um-test-server-{}.log", ::std::process::id()));
+
+        let mut server = Server::open(&path).unwrap();
+        let (a, _)      = server.connect();
+        let (b, events) = server.connect();
+        assert!(!server.execute(b, "create . services map".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "watch services".parse().unwrap()).is_err());
+
+        assert!(!server.execute(a, "create -e services web map".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "create services.web port integer".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "begin".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "create -e services db integer".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "commit".parse().unwrap()).is_err());
+
+        // Other clients can't add nodes that would be removed along with `a`'s nodes
+        assert!(server.execute(b, "create services.web owner string".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "begin".parse().unwrap()).is_err());
+        assert!(server.execute(b, "create services.web owner string".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create services.tmp owner string".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "create -e services tmp map".parse().unwrap()).is_err());
+        match server.execute(b, "commit".parse().unwrap()) {
+            Response::Results(false, _) => {},
+            res                         => panic!("expected the transaction to fail but got {:?}", res),
+        }
+
+        // A node that was replaced by another client's node isn't removed
+        assert!(!server.execute(b, "delete services.db".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create services db integer".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create -e services cache integer".parse().unwrap()).is_err());
+        server.notify_watchers();
+
+        // Nor does a deleted ephemeral node keep others from using a new node at its path
+        assert!(!server.execute(a, "create -e . sess map".parse().unwrap()).is_err());
+        assert!(!server.execute(a, "delete sess".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create . sess map".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "create sess child integer".parse().unwrap()).is_err());
+
+        server.disconnect(a);
+        assert!(server.execute(b, "read services.web".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "read services.db".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "read services.cache".parse().unwrap()).is_err());
+
+        // Ephemeral nodes don't survive a restart, since their clients are gone
+        drop(server);
+        let mut server = Server::open(&path).unwrap();
+        assert!(server.execute(b, "read services.cache".parse().unwrap()).is_err());
+        assert!(!server.execute(b, "read services.db".parse().unwrap()).is_err());
+
+        let events: Vec<String> = events.wait().map(|e| e.unwrap().1.to_string()).collect();
+        assert_eq!(events.last().map(String::as_str), Some("delete services.web"));
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_unix_sockets() {
+        let path = ::std::env::temp_dir().join(format!("um-test-server-{}.sock", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let uds = Server::bind_unix(&path, Some(0o600)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // A socket that's still in use is left alone, one that isn't is replaced
+        assert_eq!(Server::bind_unix(&path, None).err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+        drop(uds);
+        assert!(Server::bind_unix(&path, None).is_ok());
+
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,360 @@
+use bytes::{Buf, BufMut, BytesMut};
+use node::Node;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use value::{Map, Value};
+
+/// Every snapshot file starts with these bytes, followed by the format version.
+const MAGIC: &[u8] = b"um snapshot\n";
+
+/// Version 1 only holds values, version 2 adds the version of every node, version 3 adds when
+/// nodes expire, and version 4 adds lists.
+pub const FORMAT_VERSION: u32 = 4;
+
+const TAG_EMPTY:   u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_INTEGER: u8 = 2;
+const TAG_FLOAT:   u8 = 3;
+const TAG_STRING:  u8 = 4;
+const TAG_MAP:     u8 = 5;
+const TAG_LIST:    u8 = 6;
+
+/// Writes the tree under `root` to the snapshot file at `path`. Ephemeral nodes belong to
+/// connected clients, so they're left out.
+///
+/// The snapshot is written next to `path` first, and then moved over it, so an existing
+/// snapshot is never left half-overwritten.
+pub fn save(root: &Node, path: &Path) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    buf.extend_from_slice(MAGIC);
+    buf.reserve(4);
+    buf.put_u32_be(FORMAT_VERSION);
+    encode_node(root, &mut buf);
+
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&buf)?;
+        tmp.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)
+}
+
+/// Reads the tree from the snapshot file at `path`.
+pub fn load(path: &Path) -> io::Result<Node> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if !data.starts_with(MAGIC) {
+        return Err(invalid("not a snapshot file"));
+    }
+
+    let mut buf = Cursor::new(&data[MAGIC.len()..]);
+    need(&buf, 4)?;
+    let format = buf.get_u32_be();
+    if format == 0 || format > FORMAT_VERSION {
+        return Err(invalid("unsupported snapshot format version"));
+    }
+
+    let root = decode_node(&mut buf, format)?;
+    if buf.has_remaining() {
+        return Err(invalid("trailing data after snapshot"));
+    }
+    if let Value::Map(_) = root.value() {} else {
+        return Err(invalid("root node is not a map"));
+    }
+
+    Ok(root)
+}
+
+fn encode_node(node: &Node, buf: &mut BytesMut) {
+    buf.reserve(25);
+    buf.put_u64_be(node.version());
+    // Nodes can't expire at the unix epoch, so zero means never
+    buf.put_u64_be(node.expires().unwrap_or(0));
+    match node.value() {
+        Value::Empty => {
+            buf.put_u8(TAG_EMPTY);
+        },
+        Value::Boolean(b) => {
+            buf.put_u8(TAG_BOOLEAN);
+            buf.put_u8(*b as u8);
+        },
+        Value::Integer(i) => {
+            buf.put_u8(TAG_INTEGER);
+            buf.put_i64_be(*i);
+        },
+        Value::Float(f) => {
+            // Storing the bits keeps NaN payloads and the sign of zero intact
+            buf.put_u8(TAG_FLOAT);
+            buf.put_u64_be(f.to_bits());
+        },
+        Value::String(s) => {
+            buf.put_u8(TAG_STRING);
+            encode_str(s, buf);
+        },
+        Value::Map(m) => {
+            let children: Vec<_> = m.iter().filter(|(_, child)| !child.is_ephemeral()).collect();
+            buf.put_u8(TAG_MAP);
+            buf.put_u32_be(children.len() as u32);
+            for (name, child) in children {
+                encode_str(name, buf);
+                encode_node(child, buf);
+            }
+        },
+        Value::List(l) => {
+            // Nodes in lists can't be ephemeral
+            buf.put_u8(TAG_LIST);
+            buf.put_u32_be(l.len() as u32);
+            for item in l {
+                encode_node(item, buf);
+            }
+        },
+    }
+}
+
+fn encode_str(s: &str, buf: &mut BytesMut) {
+    buf.reserve(4 + s.len());
+    buf.put_u32_be(s.len() as u32);
+    buf.put_slice(s.as_bytes());
+}
+
+fn decode_node(buf: &mut Cursor<&[u8]>, format: u32) -> io::Result<Node> {
+    let version = if format >= 2 {
+        need(buf, 8)?;
+        buf.get_u64_be()
+    } else {
+        0
+    };
+
+    let expires = if format >= 3 {
+        need(buf, 8)?;
+        Some(buf.get_u64_be()).filter(|&t| t != 0)
+    } else {
+        None
+    };
+
+    need(buf, 1)?;
+    let value = match buf.get_u8() {
+        TAG_EMPTY => Value::Empty,
+        TAG_BOOLEAN => {
+            need(buf, 1)?;
+            match buf.get_u8() {
+                0 => Value::Boolean(false),
+                1 => Value::Boolean(true),
+                _ => return Err(invalid("invalid boolean")),
+            }
+        },
+        TAG_INTEGER => {
+            need(buf, 8)?;
+            Value::Integer(buf.get_i64_be())
+        },
+        TAG_FLOAT => {
+            need(buf, 8)?;
+            Value::Float(f64::from_bits(buf.get_u64_be()))
+        },
+        TAG_STRING => Value::String(decode_str(buf)?),
+        TAG_MAP => {
+            need(buf, 4)?;
+            let len   = buf.get_u32_be();
+            let mut m = Map::new();
+            for _ in 0..len {
+                let name  = decode_str(buf)?;
+                let child = decode_node(buf, format)?;
+                if m.insert(name, child).is_some() {
+                    return Err(invalid("duplicate node name"));
+                }
+            }
+            Value::Map(m)
+        },
+        TAG_LIST if format >= 4 => {
+            need(buf, 4)?;
+            let len = buf.get_u32_be();
+            let mut l = Vec::new();
+            for _ in 0..len {
+                l.push(decode_node(buf, format)?);
+            }
+            Value::List(l)
+        },
+        _ => return Err(invalid("invalid value type")),
+    };
+
+    let mut node = Node::with_value(value);
+    node.set_version(version);
+    node.set_expires(expires);
+    Ok(node)
+}
+
+fn decode_str(buf: &mut Cursor<&[u8]>) -> io::Result<String> {
+    need(buf, 4)?;
+    let len = buf.get_u32_be() as usize;
+    need(buf, len)?;
+
+    let mut bytes = vec![0; len];
+    buf.copy_to_slice(&mut bytes);
+    String::from_utf8(bytes).map_err(|_| invalid("invalid utf-8"))
+}
+
+/// Makes sure at least `n` more bytes can be read from `buf`.
+fn need(buf: &Cursor<&[u8]>, n: usize) -> io::Result<()> {
+    if buf.remaining() < n {
+        Err(invalid("unexpected end of snapshot"))
+    } else {
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::f64;
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("um-test-{}-{}.snapshot", name, ::std::process::id()))
+    }
+
+    fn child<'a>(node: &'a Node, name: &str) -> &'a Value {
+        match node.value() {
+            Value::Map(m) => m[name].value(),
+            val           => panic!("expected a map but got {:?}", val),
+        }
+    }
+
+    #[test]
+    fn roundtrip_values() {
+        let mut inner = Map::new();
+        inner.insert("string".to_string(), Node::with_value(Value::String("hi there".to_string())));
+
+        let mut m = Map::new();
+        m.insert("empty".to_string(),    Node::with_value(Value::Empty));
+        m.insert("true".to_string(),     Node::with_value(Value::Boolean(true)));
+        m.insert("integer".to_string(),  Node::with_value(Value::Integer(i64::min_value())));
+        m.insert("nan".to_string(),      Node::with_value(Value::Float(f64::NAN)));
+        m.insert("negzero".to_string(),  Node::with_value(Value::Float(-0.0)));
+        m.insert("infinity".to_string(), Node::with_value(Value::Float(f64::NEG_INFINITY)));
+        m.insert("map".to_string(),      Node::with_value(Value::Map(inner)));
+        m.insert("list".to_string(),     Node::with_value(Value::List(vec![
+            Node::with_value(Value::Integer(3)),
+            Node::with_value(Value::String("two".to_string())),
+        ])));
+        m.get_mut("integer").unwrap().set_version(42);
+        m.get_mut("true").unwrap().set_expires(Some(1500000000000));
+        m.insert("session".to_string(), Node::with_value(Value::Integer(1)));
+        m.get_mut("session").unwrap().set_ephemeral(true);
+        let root = Node::with_value(Value::Map(m));
+
+        let path = snapshot_path("roundtrip");
+        save(&root, &path).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        match child(&loaded, "nan") {
+            Value::Float(f) => assert_eq!(f.to_bits(), f64::NAN.to_bits()),
+            val             => panic!("expected a float but got {:?}", val),
+        }
+        match child(&loaded, "negzero") {
+            Value::Float(f) => assert_eq!(f.to_bits(), (-0.0f64).to_bits()),
+            val             => panic!("expected a float but got {:?}", val),
+        }
+
+        assert_eq!(child(&loaded, "empty").to_string(),    "empty");
+        assert_eq!(child(&loaded, "true").to_string(),     "boolean true");
+        assert_eq!(child(&loaded, "integer").to_string(),  "integer -9223372036854775808");
+        assert_eq!(child(&loaded, "infinity").to_string(), "float -inf");
+        assert_eq!(child(&loaded, "map").to_string(),      "map 1");
+        assert_eq!(child(&loaded, "list").to_string(),     "list 2");
+
+        match loaded.value() {
+            Value::Map(m) => {
+                assert_eq!(m["integer"].version(), 42);
+                assert_eq!(m["true"].expires(), Some(1500000000000));
+                assert_eq!(m["empty"].expires(), None);
+                assert!(!m.contains_key("session"));
+            },
+            val           => panic!("expected a map but got {:?}", val),
+        }
+
+        let map = Node::with_value(child(&loaded, "map").clone());
+        assert_eq!(child(&map, "string").to_string(), "string :hi there");
+
+        match child(&loaded, "list") {
+            Value::List(l) => {
+                assert_eq!(l[0].value().to_string(), "integer 3");
+                assert_eq!(l[1].value().to_string(), "string :two");
+            },
+            val            => panic!("expected a list but got {:?}", val),
+        }
+    }
+
+    #[test]
+    fn load_format_version_1() {
+        let path = snapshot_path("version1");
+
+        // Version 1 files don't have node versions
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[ 0, 0, 0, 1, TAG_MAP, 0, 0, 0, 1, 0, 0, 0, 1, b'a', TAG_INTEGER, 0, 0, 0, 0, 0, 0, 0, 7 ]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let root = load(&path).unwrap();
+        assert_eq!(child(&root, "a").to_string(), "integer 7");
+        assert_eq!(root.version(), 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_format_version_2() {
+        let path = snapshot_path("version2");
+
+        // Version 2 files don't have expiry times
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[ 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 3, TAG_MAP, 0, 0, 0, 1,
+                                  0, 0, 0, 1, b't', 0, 0, 0, 0, 0, 0, 0, 0, TAG_BOOLEAN, 1 ]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let root = load(&path).unwrap();
+        assert_eq!(child(&root, "t").to_string(), "boolean true");
+        assert_eq!(root.version(), 3);
+        assert_eq!(root.expires(), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        let path = snapshot_path("invalid");
+
+        File::create(&path).unwrap().write_all(b"not a snapshot").unwrap();
+        assert!(load(&path).is_err());
+
+        // Unknown format version
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[ 0, 0, 0, 99, TAG_EMPTY ]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert!(load(&path).is_err());
+
+        // Truncated in the middle of a string
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[ 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, TAG_STRING, 0, 0, 0, 10, b'a' ]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert!(load(&path).is_err());
+
+        // The root has to be a map
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&[ 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, TAG_EMPTY ]);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert!(load(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
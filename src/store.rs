@@ -1,11 +1,38 @@
 use command::Command;
+use event::Event;
+use expiry::Expiry;
 use node::Node;
 use nodespec::NodeSpec;
+use pattern::{Pattern, Segment};
 use response::Response;
+use std::collections::BTreeSet;
+use std::mem;
 use value::{ValType, Value};
 
 pub struct Store {
     root: Node,
+    events: Vec<Event>,
+    journal: Option<Journal>,
+
+    /// The current time in milliseconds since the unix epoch, as of the last call to `reap`.
+    /// Nodes that expired before it are hidden.
+    now: u64,
+    /// When nodes expire, in order. Entries for nodes that were removed or got a different
+    /// expiry time are skipped when reaping.
+    deadlines: BTreeSet<(u64, NodeSpec)>,
+}
+
+/// Keeps track of the changes made during a transaction, so they can be rolled back.
+struct Journal {
+    undo: Vec<Undo>,
+    events: usize,
+}
+
+enum Undo {
+    /// Remove a node that was created
+    Remove(NodeSpec),
+    /// Put back a node that was updated or deleted
+    Restore(NodeSpec, Node),
 }
 
 impl Store {
@@ -14,45 +41,660 @@ impl Store {
 
         Store {
             root,
+            events: Vec::new(),
+            journal: None,
+            now: 0,
+            deadlines: BTreeSet::new(),
         }
     }
 
     pub fn execute(&mut self, cmd: Command) -> Response {
         match cmd {
-            Command::Create(nodespec, name, valtype) => {
-                let parent = self.get_node(nodespec)?;
+            Command::Create(mut nodespec, name, valtype, expiry, ephemeral) => {
+                if expiry.is_some() || ephemeral {
+                    let mut childspec = nodespec.clone();
+                    childspec.push(name.clone());
+                    if self.below_list(&childspec) {
+                        return Response::Error("nodes in lists can't expire or be ephemeral");
+                    }
+                }
+
+                let mut node = Node::with_type(&valtype);
+                let expires  = expiry.map(|e| e.deadline(self.now));
+                node.set_expires(expires);
+                node.set_ephemeral(ephemeral);
+
+                let parent = self.get_node(&nodespec)?;
                 match parent.value_mut() {
                     Value::Map(m) => {
                         if m.contains_key(&name) {
                             return Response::Error("node already exists");
                         }
-                        m.insert(name, Node::with_type(&valtype));
+                        m.insert(name.clone(), node.clone());
                     },
                     _             => return Response::Error("parents exist but is not a map"),
                 }
+                nodespec.push(name);
+                if let Some(expires) = expires {
+                    self.deadlines.insert((expires, nodespec.clone()));
+                }
+                self.journal(Undo::Remove(nodespec.clone()));
+                self.events.push(Event::Create(nodespec, node.value().clone()));
                 Response::Success
             },
             Command::Read(nodespec) => {
-                Response::Value(self.get_node(nodespec)?.read_value())
+                Response::Value(self.get_node(&nodespec)?.read_value())
+            },
+            Command::ReadMatching(pattern) => {
+                let nodes = self.matches(&pattern).into_iter()
+                    .map(|(nodespec, node)| (nodespec, node.read_value()))
+                    .collect();
+                Response::Tree(nodes)
+            },
+            Command::ReadVersion(nodespec) => {
+                let node = self.get_node(&nodespec)?;
+                Response::Versioned(node.version(), node.read_value())
+            },
+            Command::Dump(nodespec, depth) => {
+                let mut nodes = Vec::new();
+                let now = self.now;
+                Self::dump_node(self.get_node(&nodespec)?, nodespec, depth, now, &mut nodes);
+                Response::Tree(nodes)
+            },
+            Command::List(nodespec, opts) => {
+                let now = self.now;
+                let m = match self.get_node(&nodespec)?.read_value() {
+                    Value::Map(m) => m,
+                    _             => return Response::Error("node is not a map"),
+                };
+
+                let prefix = opts.prefix.as_ref().map_or("", String::as_str);
+                let mut names: Vec<&String> = m.keys()
+                    .filter(|name| name.starts_with(prefix) && !m[*name].is_expired(now))
+                    .filter(|name| opts.cursor.as_ref().map_or(true, |c| *name > c))
+                    .collect();
+                if opts.sorted() {
+                    names.sort();
+                }
+
+                let mut next = None;
+                if let Some(limit) = opts.limit {
+                    if names.len() > limit {
+                        names.truncate(limit);
+                        next = Some(names[limit - 1].as_str());
+                    }
+                }
+
+                let children = names.into_iter()
+                    .map(|name| (name.as_str(), m[name].value().valtype()))
+                    .collect();
+                Response::List(children, next)
+            },
+            Command::Update(nodespec, value, expiry) => {
+                if expiry.is_some() && self.below_list(&nodespec) {
+                    return Response::Error("nodes in lists can't expire");
+                }
+                self.modify(nodespec.clone(), None, |val| Value::from_str(&value, &val.valtype()))?;
+                if let Some(expiry) = expiry {
+                    let expires = expiry.deadline(self.now);
+                    self.set_expires(nodespec, Some(expires))?;
+                }
+                Response::Success
+            },
+            Command::Set(nodespec, value, expiry) => {
+                if self.get_node(&nodespec).is_err() {
+                    let mut parent = nodespec.clone();
+                    let name = parent.pop().ok_or("node does not exist")?;
+                    self.execute(Command::Create(parent, name, ValType::String, None, false))?;
+                }
+                self.execute(Command::Update(nodespec, value, expiry))
+            },
+            Command::UpdateMatching(pattern, value) => {
+                let nodespecs: Vec<NodeSpec> = self.matches(&pattern).into_iter()
+                    .map(|(nodespec, _)| nodespec)
+                    .collect();
+
+                // Update all matching nodes or none of them, also outside of transactions
+                let own_journal = self.journal.is_none();
+                if own_journal {
+                    self.begin();
+                }
+                for nodespec in nodespecs {
+                    if let Err(e) = self.modify(nodespec, None, |val| Value::from_str(&value, &val.valtype())) {
+                        if own_journal {
+                            self.rollback();
+                        }
+                        return Response::Error(e);
+                    }
+                }
+                if own_journal {
+                    self.commit();
+                }
+
+                let nodes = self.matches(&pattern).into_iter()
+                    .map(|(nodespec, node)| (nodespec, node.read_value()))
+                    .collect();
+                Response::Tree(nodes)
+            },
+            Command::Cas(nodespec, version, value) => {
+                self.modify(nodespec, Some(version), |val| Value::from_str(&value, &val.valtype()))?;
+                Response::Success
+            },
+            Command::Incr(nodespec, amount) => {
+                let amount = amount.as_ref().map_or("1", String::as_str);
+                self.modify(nodespec.clone(), None, |val| val.add(amount, false))?;
+                Response::Value(self.get_node(&nodespec)?.read_value())
+            },
+            Command::Decr(nodespec, amount) => {
+                let amount = amount.as_ref().map_or("1", String::as_str);
+                self.modify(nodespec.clone(), None, |val| val.add(amount, true))?;
+                Response::Value(self.get_node(&nodespec)?.read_value())
+            },
+            Command::Append(nodespec, s) => {
+                self.modify(nodespec.clone(), None, |val| val.concat(&s, false))?;
+                Response::Value(self.get_node(&nodespec)?.read_value())
+            },
+            Command::Prepend(nodespec, s) => {
+                self.modify(nodespec.clone(), None, |val| val.concat(&s, true))?;
+                Response::Value(self.get_node(&nodespec)?.read_value())
+            },
+            Command::Toggle(nodespec) => {
+                self.modify(nodespec.clone(), None, Value::toggle)?;
+                Response::Value(self.get_node(&nodespec)?.read_value())
+            },
+            Command::Delete(mut nodespec, recursive) => {
+                let name   = nodespec.pop().ok_or("can't delete the root node")?;
+                if let Value::List(_) = self.get_node(&nodespec)?.value() {
+                    let index = self.modify_list(&nodespec, |l| {
+                        let index = item_index(l, &name).ok_or("node does not exist")?;
+                        Self::check_empty(&l[index], recursive)?;
+                        l.remove(index);
+                        Ok(index)
+                    })?;
+                    nodespec.push(index.to_string());
+                    self.events.push(Event::Delete(nodespec));
+                    return Response::Success;
+                }
+
+                let parent = self.get_node(&nodespec)?;
+                let removed = match parent.value_mut() {
+                    Value::Map(m) => {
+                        Self::check_empty(m.get(&name).ok_or("node does not exist")?, recursive)?;
+                        m.remove(&name).unwrap()
+                    },
+                    _             => return Response::Error("node does not exist (some parent node does but is not a map)"),
+                };
+                nodespec.push(name);
+                self.journal(Undo::Restore(nodespec.clone(), removed));
+                self.events.push(Event::Delete(nodespec));
+                Response::Success
             },
-            Command::Update(nodespec, value) => {
-                self.get_node(nodespec)?.update_value(&value)?;
+            Command::Push(mut nodespec, valtype, value) => {
+                let item  = Self::new_item(&valtype, value)?;
+                let value = item.value().clone();
+                let index = self.modify_list(&nodespec, |l| {
+                    l.push(item);
+                    Ok(l.len() - 1)
+                })?;
+                nodespec.push(index.to_string());
+                self.events.push(Event::Create(nodespec, value));
                 Response::Success
+            },
+            Command::Insert(mut nodespec, index, valtype, value) => {
+                let item  = Self::new_item(&valtype, value)?;
+                let value = item.value().clone();
+                let index = self.modify_list(&nodespec, |l| {
+                    let index = list_index(l.len(), index, true).ok_or("index out of range")?;
+                    l.insert(index, item);
+                    Ok(index)
+                })?;
+                // The items after it move up without events of their own, so watchers have to
+                // watch the whole list
+                nodespec.push(index.to_string());
+                self.events.push(Event::Create(nodespec, value));
+                Response::Success
+            },
+            Command::Pop(mut nodespec, index) => {
+                let (index, item) = self.modify_list(&nodespec, |l| {
+                    let index = match index {
+                        Some(i) => list_index(l.len(), i, false).ok_or("index out of range")?,
+                        None    => l.len().checked_sub(1).ok_or("list is empty")?,
+                    };
+                    Ok((index, l.remove(index)))
+                })?;
+                nodespec.push(index.to_string());
+                self.events.push(Event::Delete(nodespec));
+                Response::Popped(item.into_value())
+            },
+            Command::Ttl(nodespec) => {
+                let expires = self.get_node(&nodespec)?.expires();
+                Response::Ttl(expires.map(|t| self.remaining_secs(t)))
+            },
+            Command::Expire(nodespec, expiry) => {
+                let expires = expiry.map(|e| e.deadline(self.now));
+                self.set_expires(nodespec, expires)?;
+                Response::Ttl(expires.map(|t| self.remaining_secs(t)))
+            },
+            _ => Response::Error("command is not supported by the store"),
+        }
+    }
+
+    /// Replaces the value of a node with the one computed from it by `f`, but only if its
+    /// version is `expected_version` when given.
+    fn modify<F>(&mut self, nodespec: NodeSpec, expected_version: Option<u64>, f: F) -> Result<(), &'static str> where
+        F: FnOnce(&Value) -> Result<Value, &'static str>
+    {
+        let journaling = self.journal.is_some();
+
+        let node = self.get_node(&nodespec)?;
+        if expected_version.map_or(false, |v| v != node.version()) {
+            return Err("version mismatch");
+        }
+
+        let old = if journaling { Some(node.clone()) } else { None };
+        node.modify_value(f)?;
+        let value = node.value().clone();
+
+        if let Some(old) = old {
+            self.journal(Undo::Restore(nodespec.clone(), old));
+        }
+        self.events.push(Event::Update(nodespec, value));
+        Ok(())
+    }
+
+    /// Adds or removes items of a list through `f`. Items move when others are added or
+    /// removed before them, so the whole list is kept for rollbacks.
+    fn modify_list<F, T>(&mut self, nodespec: &NodeSpec, f: F) -> Result<T, &'static str> where
+        F: FnOnce(&mut Vec<Node>) -> Result<T, &'static str>
+    {
+        let journaling = self.journal.is_some();
+
+        let node = self.get_node(nodespec)?;
+        let old  = if journaling { Some(node.clone()) } else { None };
+        let res  = match node.value_mut() {
+            Value::List(l) => f(l)?,
+            _              => return Err("node is not a list"),
+        };
+
+        if let Some(old) = old {
+            self.journal(Undo::Restore(nodespec.clone(), old));
+        }
+        Ok(res)
+    }
+
+    /// Returns a new list item, with `value` parsed as `valtype` if given.
+    fn new_item(valtype: &ValType, value: Option<String>) -> Result<Node, &'static str> {
+        let mut node = Node::with_type(valtype);
+        if let Some(value) = value {
+            *node.value_mut() = Value::from_str(&value, valtype)?;
+        }
+        Ok(node)
+    }
+
+    /// Fails if a node has children, unless they're to be deleted along with it.
+    fn check_empty(node: &Node, recursive: bool) -> Result<(), &'static str> {
+        match node.value() {
+            Value::Map(c) if !c.is_empty() && !recursive => Err("map is not empty (use delete -r)"),
+            Value::List(c) if !c.is_empty() && !recursive => Err("list is not empty (use delete -r)"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if the node at `nodespec` would be below a list. Such nodes move around
+    /// when items are added or removed, so they can't expire or be ephemeral.
+    fn below_list(&self, nodespec: &NodeSpec) -> bool {
+        let mut node = &self.root;
+        for name in nodespec.iter() {
+            node = match node.value() {
+                Value::Map(m)  => match m.get(name) {
+                    Some(child) => child,
+                    None        => return false,
+                },
+                Value::List(_) => return true,
+                _              => return false,
+            };
+        }
+        false
+    }
+
+    /// Changes when a node expires, without counting as an update of its value.
+    fn set_expires(&mut self, nodespec: NodeSpec, expires: Option<u64>) -> Result<(), &'static str> {
+        let journaling = self.journal.is_some();
+
+        if expires.is_some() && self.below_list(&nodespec) {
+            return Err("nodes in lists can't expire");
+        }
+
+        let node = self.get_node(&nodespec)?;
+        if nodespec.iter().next().is_none() {
+            return Err("the root node can't expire");
+        }
+
+        let old = if journaling { Some(node.clone()) } else { None };
+        node.set_expires(expires);
+
+        if let Some(old) = old {
+            self.journal(Undo::Restore(nodespec.clone(), old));
+        }
+        if let Some(expires) = expires {
+            self.deadlines.insert((expires, nodespec));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of seconds from now until `deadline`, rounded up.
+    fn remaining_secs(&self, deadline: u64) -> u64 {
+        (deadline.saturating_sub(self.now) + 999) / 1000
+    }
+
+    /// Advances the clock to `now` and removes every node that has expired by then. Returns
+    /// the nodespecs of the removed nodes.
+    pub fn reap(&mut self, now: u64) -> Vec<NodeSpec> {
+        self.now = now;
+
+        let mut reaped = Vec::new();
+        while let Some((deadline, nodespec)) = self.deadlines.iter().next().cloned() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.remove(&(deadline, nodespec.clone()));
+
+            let mut parentspec = nodespec.clone();
+            let name = parentspec.pop().unwrap();
+
+            // The node may have been removed already, possibly along with an expired parent
+            if let Ok(parent) = self.get_node(&parentspec) {
+                if let Value::Map(m) = parent.value_mut() {
+                    if m.get(&name).map_or(false, |n| n.expires() == Some(deadline)) {
+                        m.remove(&name);
+                        self.events.push(Event::Delete(nodespec.clone()));
+                        reaped.push(nodespec);
+                    }
+                }
             }
         }
+
+        reaped
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
     }
 
-    pub fn get_node(&mut self, nodespec: NodeSpec) -> Result<&mut Node, &'static str> {
+    pub fn get_node(&mut self, nodespec: &NodeSpec) -> Result<&mut Node, &'static str> {
+        let now = self.now;
         let mut iter = &mut self.root;
         for childname in nodespec.iter() {
-            if let &mut Value::Map(ref mut m) = iter.value_mut() {
-                iter = m.get_mut(childname).ok_or("node does not exist")?;
-            } else {
-                return Err("node does not exist (some parent node does but is not a map)");
+            iter = match iter.value_mut() {
+                Value::Map(m)  => m.get_mut(childname).filter(|n| !n.is_expired(now)).ok_or("node does not exist")?,
+                Value::List(l) => {
+                    let index = item_index(l, childname).ok_or("node does not exist")?;
+                    &mut l[index]
+                },
+                _              => return Err("node does not exist (some parent node does but is not a map)"),
             };
         }
         Ok(iter)
     }
+
+    /// Returns the node at `nodespec` without needing mutable access, if it exists.
+    pub fn node(&self, nodespec: &NodeSpec) -> Option<&Node> {
+        let mut iter = &self.root;
+        for childname in nodespec.iter() {
+            iter = match iter.value() {
+                Value::Map(m)  => m.get(childname).filter(|n| !n.is_expired(self.now))?,
+                Value::List(l) => &l[item_index(l, childname)?],
+                _              => return None,
+            };
+        }
+        Some(iter)
+    }
+
+    /// Returns all nodes matching `pattern`, ordered by path.
+    pub fn matches(&self, pattern: &Pattern) -> Vec<(NodeSpec, &Node)> {
+        let mut matches = Vec::new();
+        Self::match_node(&self.root, NodeSpec::root(), pattern.segments(), self.now, &mut matches);
+
+        // `**` and overlapping alternatives can match the same node more than once
+        matches.sort_by(|a, b| a.0.iter().cmp(b.0.iter()));
+        matches.dedup_by(|a, b| a.0 == b.0);
+        matches
+    }
+
+    fn match_node<'a>(node: &'a Node, nodespec: NodeSpec, segments: &[Segment], now: u64,
+                      matches: &mut Vec<(NodeSpec, &'a Node)>) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None        => return matches.push((nodespec, node)),
+        };
+
+        // `**` can also match no levels at all
+        if let Segment::AnyDepth = segment {
+            Self::match_node(node, nodespec.clone(), rest, now, matches);
+        }
+
+        for (name, child) in node.value().children() {
+            if segment.matches(&name) && !child.is_expired(now) {
+                let mut childspec = nodespec.clone();
+                childspec.push(name.into_owned());
+
+                // `**` stays in effect for the levels below
+                let remaining = if let Segment::AnyDepth = segment { segments } else { rest };
+                Self::match_node(child, childspec, remaining, now, matches);
+            }
+        }
+    }
+
+    /// Collects the node and its descendants, up to `depth` levels below it, ordered by path.
+    fn dump_node<'a>(node: &'a Node, nodespec: NodeSpec, depth: Option<usize>, now: u64,
+                     nodes: &mut Vec<(NodeSpec, &'a Value)>) {
+        nodes.push((nodespec.clone(), node.read_value()));
+
+        if depth == Some(0) {
+            return;
+        }
+
+        let mut children: Vec<_> = node.value().children().filter(|(_, child)| !child.is_expired(now)).collect();
+        // List items stay in the order of their index
+        if let Value::Map(_) = node.value() {
+            children.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        for (name, child) in children {
+            let mut childspec = nodespec.clone();
+            childspec.push(name.into_owned());
+            Self::dump_node(child, childspec, depth.map(|d| d - 1), now, nodes);
+        }
+    }
+
+    /// Starts keeping track of changes, so they can be rolled back.
+    pub fn begin(&mut self) {
+        self.journal = Some(Journal {
+            undo: Vec::new(),
+            events: self.events.len(),
+        });
+    }
+
+    /// Keeps the changes made since `begin`.
+    pub fn commit(&mut self) {
+        self.journal = None;
+    }
+
+    /// Undoes all changes made since `begin`, and drops their events.
+    pub fn rollback(&mut self) {
+        let journal = match self.journal.take() {
+            Some(journal) => journal,
+            None          => return,
+        };
+
+        for undo in journal.undo.into_iter().rev() {
+            let (mut nodespec, node) = match undo {
+                Undo::Remove(nodespec)        => (nodespec, None),
+                Undo::Restore(nodespec, node) => (nodespec, Some(node)),
+            };
+
+            let name = nodespec.pop().unwrap();
+            if let Ok(parent) = self.get_node(&nodespec) {
+                match (parent.value_mut(), node) {
+                    (Value::Map(m), Some(node)) => { m.insert(name, node); },
+                    (Value::Map(m), None)       => { m.remove(&name); },
+                    (Value::List(l), Some(node)) => {
+                        if let Some(index) = item_index(l, &name) {
+                            l[index] = node;
+                        }
+                    },
+                    _ => (),
+                }
+            }
+        }
+
+        self.events.truncate(journal.events);
+    }
+
+    fn journal(&mut self, undo: Undo) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.undo.push(undo);
+        }
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    /// Replaces the whole tree with the one under `root`, e.g. after loading a snapshot.
+    pub fn replace_root(&mut self, root: Node) -> Result<(), &'static str> {
+        if let Value::Map(_) = root.value() {} else {
+            return Err("root node is not a map");
+        }
+        let old = mem::replace(&mut self.root, root);
+
+        self.deadlines.clear();
+        Self::deadlines_node(&self.root, &NodeSpec::root(), &mut self.deadlines);
+
+        if let Value::Map(m) = old.value() {
+            for name in m.keys() {
+                let mut nodespec = NodeSpec::root();
+                nodespec.push(name.clone());
+                self.events.push(Event::Delete(nodespec));
+            }
+        }
+
+        if let Value::Map(m) = self.root.value() {
+            for (name, child) in m {
+                let mut nodespec = NodeSpec::root();
+                nodespec.push(name.clone());
+                self.events.push(Event::Create(nodespec, child.value().clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deadlines_node(node: &Node, nodespec: &NodeSpec, deadlines: &mut BTreeSet<(u64, NodeSpec)>) {
+        if let Some(expires) = node.expires() {
+            deadlines.insert((expires, nodespec.clone()));
+        }
+
+        for (name, child) in node.value().children() {
+            let mut childspec = nodespec.clone();
+            childspec.push(name.into_owned());
+            Self::deadlines_node(child, &childspec, deadlines);
+        }
+    }
+
+    /// Returns a list of commands that rebuilds the current tree when executed on an empty
+    /// store. Values that are the default for their type aren't set explicitly.
+    pub fn snapshot(&self) -> Vec<Command> {
+        let mut cmds = Vec::new();
+        Self::snapshot_node(&self.root, &NodeSpec::root(), &mut cmds);
+        cmds
+    }
+
+    fn snapshot_node(node: &Node, nodespec: &NodeSpec, cmds: &mut Vec<Command>) {
+        let in_list = if let Value::List(_) = node.value() { true } else { false };
+
+        for (name, child) in node.value().children() {
+            let name    = name.into_owned();
+            let valtype = child.value().valtype();
+            let default = Node::with_type(&valtype).value().payload();
+            let payload = child.value().payload().filter(|p| Some(p) != default.as_ref());
+
+            let mut childspec = nodespec.clone();
+            childspec.push(name.clone());
+
+            // Pushing the items of a list in order puts them back at the same index
+            if in_list {
+                cmds.push(Command::Push(nodespec.clone(), valtype, payload));
+            } else {
+                let expiry = child.expires().map(Expiry::At);
+                cmds.push(Command::Create(nodespec.clone(), name, valtype, expiry, child.is_ephemeral()));
+                if let Some(payload) = payload {
+                    cmds.push(Command::Update(childspec.clone(), payload, None));
+                }
+            }
+
+            Self::snapshot_node(child, &childspec, cmds);
+        }
+    }
+
+    /// Returns the version of every node that has been updated at least once.
+    pub fn versions(&self) -> Vec<(NodeSpec, u64)> {
+        let mut versions = Vec::new();
+        Self::versions_node(&self.root, &NodeSpec::root(), &mut versions);
+        versions
+    }
+
+    fn versions_node(node: &Node, nodespec: &NodeSpec, versions: &mut Vec<(NodeSpec, u64)>) {
+        if node.version() > 0 {
+            versions.push((nodespec.clone(), node.version()));
+        }
+
+        for (name, child) in node.value().children() {
+            let mut childspec = nodespec.clone();
+            childspec.push(name.into_owned());
+            Self::versions_node(child, &childspec, versions);
+        }
+    }
+
+    /// Returns every ephemeral node that isn't below another ephemeral node.
+    pub fn ephemeral_nodes(&self) -> Vec<NodeSpec> {
+        let mut nodespecs = Vec::new();
+        Self::ephemeral_node(&self.root, &NodeSpec::root(), &mut nodespecs);
+        nodespecs
+    }
+
+    fn ephemeral_node(node: &Node, nodespec: &NodeSpec, nodespecs: &mut Vec<NodeSpec>) {
+        if node.is_ephemeral() {
+            return nodespecs.push(nodespec.clone());
+        }
+
+        for (name, child) in node.value().children() {
+            let mut childspec = nodespec.clone();
+            childspec.push(name.into_owned());
+            Self::ephemeral_node(child, &childspec, nodespecs);
+        }
+    }
+
+    /// Takes the events for all changes made since the last call.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        mem::replace(&mut self.events, Vec::new())
+    }
+}
+
+/// Returns the position of the list item at `index`, which counts from the end if negative.
+/// With `insert`, the position just past the last item is valid too.
+fn list_index(len: usize, index: i64, insert: bool) -> Option<usize> {
+    let len   = len as i64;
+    let index = if index < 0 { len + index } else { index };
+    if index >= 0 && (index < len || insert && index == len) {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
+
+/// Returns the position of the list item named `name` in a nodespec.
+fn item_index(l: &[Node], name: &str) -> Option<usize> {
+    name.parse().ok().and_then(|index| list_index(l.len(), index, false))
 }
 
 #[cfg(test)]
@@ -94,4 +736,437 @@ mod tests {
             _                                      => panic!("expected a string value but got {:?}", res),
         };
     }
+
+    #[test]
+    fn delete_nodes() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . foo map".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo bar string".parse().unwrap()).is_err());
+
+        // The root node can't be deleted
+        let res = store.execute("delete .".parse().unwrap());
+        if !res.is_err() {
+            panic!("delete . didn't fail: {:?}", res);
+        }
+
+        // Nodes that don't exist can't be deleted
+        let res = store.execute("delete foo.baz".parse().unwrap());
+        if !res.is_err() {
+            panic!("delete foo.baz didn't fail: {:?}", res);
+        }
+
+        // `foo` isn't empty, so it can only be deleted recursively
+        let res = store.execute("delete foo".parse().unwrap());
+        if !res.is_err() {
+            panic!("non-recursive delete foo didn't fail: {:?}", res);
+        }
+
+        // Delete `foo.bar`, after which `foo` is empty and can be deleted without -r
+        let res = store.execute("delete foo.bar".parse().unwrap());
+        if let Response::Success = res {} else {
+            panic!("delete foo.bar failed: {:?}", res);
+        }
+        let res = store.execute("read foo.bar".parse().unwrap());
+        if !res.is_err() {
+            panic!("foo.bar still exists after deleting: {:?}", res);
+        }
+        let res = store.execute("delete foo".parse().unwrap());
+        if let Response::Success = res {} else {
+            panic!("non-recursive delete of empty foo failed: {:?}", res);
+        }
+
+        // Delete a whole subtree
+        assert!(!store.execute("create . foo map".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo bar string".parse().unwrap()).is_err());
+        let res = store.execute("delete -r foo".parse().unwrap());
+        if let Response::Success = res {} else {
+            panic!("delete -r foo failed: {:?}", res);
+        }
+        let res = store.execute("read foo".parse().unwrap());
+        if !res.is_err() {
+            panic!("foo still exists after deleting: {:?}", res);
+        }
+    }
+
+    #[test]
+    fn snapshot_rebuilds_tree() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . foo map".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo bar string".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo baz integer".parse().unwrap()).is_err());
+        assert!(!store.execute("update foo.bar :hello world".parse().unwrap()).is_err());
+
+        let mut copy = Store::new();
+        for cmd in store.snapshot() {
+            assert!(!copy.execute(cmd).is_err());
+        }
+
+        // `foo.baz` still has its default value, so it's only created
+        assert_eq!(store.snapshot().len(), 4);
+
+        let res = copy.execute("read foo.bar".parse().unwrap());
+        match res {
+            Response::Value(&Value::String(ref s)) => assert_eq!(s, "hello world"),
+            _                                      => panic!("expected a string value but got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . counter integer".parse().unwrap()).is_err());
+        assert!(!store.execute("update counter 5".parse().unwrap()).is_err());
+
+        let res = store.execute("read -v counter".parse().unwrap());
+        match res {
+            Response::Versioned(1, &Value::Integer(5)) => (),
+            _                                          => panic!("expected version 1 of 5 but got {:?}", res),
+        };
+
+        let res = store.execute("cas counter 0 6".parse().unwrap());
+        match res {
+            Response::Error("version mismatch") => (),
+            _                                   => panic!("cas with old version didn't fail: {:?}", res),
+        };
+
+        let res = store.execute("cas counter 1 6".parse().unwrap());
+        if let Response::Success = res {} else {
+            panic!("cas counter failed: {:?}", res);
+        }
+
+        let res = store.execute("read -v counter".parse().unwrap());
+        match res {
+            Response::Versioned(2, &Value::Integer(6)) => (),
+            _                                          => panic!("expected version 2 of 6 but got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn dump_subtree() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . foo map".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo bar string".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo baz map".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo.baz qux integer".parse().unwrap()).is_err());
+        assert!(!store.execute("update foo.bar :hello world".parse().unwrap()).is_err());
+
+        let res = store.execute("dump foo".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 4\n\
+                                     foo map 2\n\
+                                     foo.bar string :hello world\n\
+                                     foo.baz map 1\n\
+                                     foo.baz.qux integer 0");
+
+        let res = store.execute("dump foo 1".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 3\nfoo map 2\nfoo.bar string :hello world\nfoo.baz map 1");
+
+        let res = store.execute("dump foo.bar 0".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 1\nfoo.bar string :hello world");
+
+        assert!(store.execute("dump nothing".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn list_children() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . foo map".parse().unwrap()).is_err());
+        for name in &[ "b", "a", "ab", "c", "abc" ] {
+            assert!(!store.execute(format!("create foo {} integer", name).parse().unwrap()).is_err());
+        }
+        assert!(!store.execute("create foo d string".parse().unwrap()).is_err());
+
+        let res = store.execute("list -s foo".parse().unwrap());
+        assert_eq!(res.to_string(), "list 6\na integer\nab integer\nabc integer\nb integer\nc integer\nd string");
+
+        let res = store.execute("list -s -p ab foo".parse().unwrap());
+        assert_eq!(res.to_string(), "list 2\nab integer\nabc integer");
+
+        // Pages continue after the name in the header
+        let res = store.execute("list -n 4 foo".parse().unwrap());
+        assert_eq!(res.to_string(), "list 4 b\na integer\nab integer\nabc integer\nb integer");
+        let res = store.execute("list -n 4 -c b foo".parse().unwrap());
+        assert_eq!(res.to_string(), "list 2\nc integer\nd string");
+
+        assert!(store.execute("list foo.a".parse().unwrap()).is_err());
+        assert!(store.execute("list bar".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn match_patterns() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . services map".parse().unwrap()).is_err());
+        for service in &[ "web", "db", "cache" ] {
+            assert!(!store.execute(format!("create services {} map", service).parse().unwrap()).is_err());
+            assert!(!store.execute(format!("create services.{} port integer", service).parse().unwrap()).is_err());
+        }
+        assert!(!store.execute("create services.web tls map".parse().unwrap()).is_err());
+        assert!(!store.execute("create services.web.tls port integer".parse().unwrap()).is_err());
+
+        let res = store.execute("read services.*.port".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 3\n\
+                                     services.cache.port integer 0\n\
+                                     services.db.port integer 0\n\
+                                     services.web.port integer 0");
+
+        let res = store.execute("read services.**.port".parse().unwrap());
+        assert_eq!(res.to_string().lines().count(), 5);
+
+        let res = store.execute("read **.{db,web}.port".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 2\nservices.db.port integer 0\nservices.web.port integer 0");
+
+        let res = store.execute("read nothing.*".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 0");
+
+        // Bulk updates change every match
+        let res = store.execute("update services.{web,db}.port 8080".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 2\nservices.db.port integer 8080\nservices.web.port integer 8080");
+
+        // ... or none of them
+        assert!(store.execute("update services.* 1".parse().unwrap()).is_err());
+        assert!(store.execute("update services.web.* 1".parse().unwrap()).is_err());
+        let res = store.execute("read services.web.port".parse().unwrap());
+        match res {
+            Response::Value(&Value::Integer(8080)) => (),
+            _                                      => panic!("expected 8080 but got {:?}", res),
+        };
+    }
+
+    #[test]
+    fn expire_nodes() {
+        let mut store = Store::new();
+        store.reap(10_000);
+        assert!(!store.execute("create . sessions map".parse().unwrap()).is_err());
+        assert!(!store.execute("create -t 5 sessions a map".parse().unwrap()).is_err());
+        assert!(!store.execute("create sessions.a user string".parse().unwrap()).is_err());
+        assert!(!store.execute("create -t @20000 sessions b string".parse().unwrap()).is_err());
+        assert!(!store.execute("create sessions c string".parse().unwrap()).is_err());
+        store.take_events();
+
+        assert_eq!(store.execute("ttl sessions.a".parse().unwrap()).to_string(), "ttl 5");
+        assert_eq!(store.execute("ttl sessions.c".parse().unwrap()).to_string(), "ttl none");
+        assert!(store.execute("ttl . 10".parse().unwrap()).is_err());
+
+        // Refreshing moves the deadline, and updates can set one too
+        assert_eq!(store.execute("ttl sessions.b 3".parse().unwrap()).to_string(), "ttl 3");
+        assert!(!store.execute("update -t 1 sessions.c :bye".parse().unwrap()).is_err());
+
+        assert_eq!(store.reap(12_000), vec![ "sessions.c".parse().unwrap() ]);
+        assert!(store.execute("read sessions.c".parse().unwrap()).is_err());
+        assert!(!store.execute("read sessions.a.user".parse().unwrap()).is_err());
+
+        assert_eq!(store.reap(15_500), vec![ "sessions.b".parse().unwrap(), "sessions.a".parse().unwrap() ]);
+        assert!(store.execute("read sessions.a.user".parse().unwrap()).is_err());
+
+        // `sessions.b` had its deadline moved, so its old one doesn't count
+        assert!(!store.execute("create sessions b string".parse().unwrap()).is_err());
+        assert!(store.reap(20_000).is_empty());
+
+        let events: Vec<String> = store.take_events().iter().map(|e| e.to_string()).collect();
+        assert_eq!(events, vec![ "update sessions.c string :bye", "delete sessions.c", "delete sessions.b",
+                                "delete sessions.a", "create sessions.b string :" ]);
+
+        // Nodes that expired are hidden even before they're reaped
+        assert!(!store.execute("create -t @21000 sessions d string".parse().unwrap()).is_err());
+        assert_eq!(store.execute("list -s sessions".parse().unwrap()).to_string(), "list 2\nb string\nd string");
+        store.now = 21_000;
+        assert!(store.execute("read sessions.d".parse().unwrap()).is_err());
+        assert_eq!(store.execute("list sessions".parse().unwrap()).to_string(), "list 1\nb string");
+    }
+
+    #[test]
+    fn ephemeral_nodes() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . services map".parse().unwrap()).is_err());
+        assert!(!store.execute("create -e services web map".parse().unwrap()).is_err());
+        assert!(!store.execute("create -e services.web leader boolean".parse().unwrap()).is_err());
+        assert!(!store.execute("create services db map".parse().unwrap()).is_err());
+        assert!(!store.execute("create -e services.db leader boolean".parse().unwrap()).is_err());
+
+        let mut ephemeral = store.ephemeral_nodes();
+        ephemeral.sort();
+        assert_eq!(ephemeral, vec![ "services.db.leader".parse().unwrap(), "services.web".parse().unwrap() ]);
+
+        // Snapshots keep nodes ephemeral
+        let mut copy = Store::new();
+        for cmd in store.snapshot() {
+            assert!(!copy.execute(cmd).is_err());
+        }
+        assert_eq!(copy.ephemeral_nodes().len(), 2);
+    }
+
+    #[test]
+    fn modify_values() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . counter integer".parse().unwrap()).is_err());
+        assert!(!store.execute("create . name string".parse().unwrap()).is_err());
+        assert!(!store.execute("create . flag boolean".parse().unwrap()).is_err());
+
+        let res = store.execute("incr counter".parse().unwrap());
+        match res {
+            Response::Value(&Value::Integer(1)) => (),
+            _                                   => panic!("expected 1 but got {:?}", res),
+        };
+        let res = store.execute("decr counter 10".parse().unwrap());
+        match res {
+            Response::Value(&Value::Integer(-9)) => (),
+            _                                    => panic!("expected -9 but got {:?}", res),
+        };
+
+        assert!(!store.execute("append name :world".parse().unwrap()).is_err());
+        let res = store.execute("prepend name :hello ".parse().unwrap());
+        match res {
+            Response::Value(&Value::String(ref s)) => assert_eq!(s, "hello world"),
+            _                                      => panic!("expected a string value but got {:?}", res),
+        };
+
+        let res = store.execute("toggle flag".parse().unwrap());
+        match res {
+            Response::Value(&Value::Boolean(true)) => (),
+            _                                      => panic!("expected true but got {:?}", res),
+        };
+
+        // Failed changes leave the node alone
+        assert!(!store.execute("update counter 9223372036854775807".parse().unwrap()).is_err());
+        assert!(store.execute("incr counter".parse().unwrap()).is_err());
+        assert!(store.execute("incr name".parse().unwrap()).is_err());
+        assert!(store.execute("toggle counter".parse().unwrap()).is_err());
+
+        let res = store.execute("read -v counter".parse().unwrap());
+        match res {
+            Response::Versioned(3, &Value::Integer(9223372036854775807)) => (),
+            _ => panic!("expected version 3 of the maximum integer but got {:?}", res),
+        };
+
+        // `set` creates string nodes, and parses the value as the type of existing ones
+        assert!(!store.execute("set counter 5".parse().unwrap()).is_err());
+        assert!(!store.execute("set greeting :hi there".parse().unwrap()).is_err());
+        assert!(store.execute("set missing.greeting hi".parse().unwrap()).is_err());
+        assert_eq!(store.execute("read counter".parse().unwrap()).to_string(), "value integer 5");
+        assert_eq!(store.execute("read greeting".parse().unwrap()).to_string(), "value string :hi there");
+    }
+
+    #[test]
+    fn rollback_changes() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . foo map".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo bar string".parse().unwrap()).is_err());
+        assert!(!store.execute("update foo.bar :before".parse().unwrap()).is_err());
+        store.take_events();
+
+        store.begin();
+        assert!(!store.execute("update foo.bar :after".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo baz map".parse().unwrap()).is_err());
+        assert!(!store.execute("create foo.baz qux integer".parse().unwrap()).is_err());
+        assert!(!store.execute("delete -r foo".parse().unwrap()).is_err());
+        assert!(!store.execute("create . foo integer".parse().unwrap()).is_err());
+        store.rollback();
+
+        assert!(store.take_events().is_empty());
+        assert!(store.execute("read foo.baz".parse().unwrap()).is_err());
+        let res = store.execute("read foo.bar".parse().unwrap());
+        match res {
+            Response::Value(&Value::String(ref s)) => assert_eq!(s, "before"),
+            _                                      => panic!("expected a string value but got {:?}", res),
+        };
+
+        // Committed changes stay
+        store.begin();
+        assert!(!store.execute("delete foo.bar".parse().unwrap()).is_err());
+        store.commit();
+        store.rollback();
+        assert!(store.execute("read foo.bar".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn change_events() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . foo integer".parse().unwrap()).is_err());
+        assert!(!store.execute("update foo 42".parse().unwrap()).is_err());
+        assert!(store.execute("update foo bar".parse().unwrap()).is_err());
+        assert!(!store.execute("read foo".parse().unwrap()).is_err());
+        assert!(!store.execute("delete foo".parse().unwrap()).is_err());
+
+        // Failed commands and reads don't produce events
+        let events: Vec<String> = store.take_events().iter().map(|e| e.to_string()).collect();
+        assert_eq!(events, vec![ "create foo integer 0", "update foo integer 42", "delete foo" ]);
+        assert!(store.take_events().is_empty());
+    }
+
+    #[test]
+    fn list_items() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . jobs list".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs string :b".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs string :d".parse().unwrap()).is_err());
+        assert!(!store.execute("insert jobs 0 string :a".parse().unwrap()).is_err());
+        assert!(!store.execute("insert jobs -1 string :c".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs map".parse().unwrap()).is_err());
+        assert!(!store.execute("create jobs.4 id integer".parse().unwrap()).is_err());
+        assert!(store.execute("insert jobs 6 string".parse().unwrap()).is_err());
+        assert!(store.execute("create jobs e string".parse().unwrap()).is_err());
+
+        let res = store.execute("read jobs".parse().unwrap());
+        assert_eq!(res.to_string(), "value list 5");
+        let res = store.execute("read jobs.-3".parse().unwrap());
+        assert_eq!(res.to_string(), "value string :c");
+        assert!(store.execute("read jobs.5".parse().unwrap()).is_err());
+        assert!(store.execute("read jobs.-6".parse().unwrap()).is_err());
+
+        let res = store.execute("pop jobs".parse().unwrap());
+        assert_eq!(res.to_string(), "value map 1");
+        let res = store.execute("pop jobs 0".parse().unwrap());
+        assert_eq!(res.to_string(), "value string :a");
+        assert!(!store.execute("update jobs.0 :B".parse().unwrap()).is_err());
+        assert!(!store.execute("delete jobs.-2".parse().unwrap()).is_err());
+
+        let res = store.execute("dump jobs".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 3\njobs list 2\njobs.0 string :B\njobs.1 string :d");
+        let res = store.execute("read jobs.*".parse().unwrap());
+        assert_eq!(res.to_string(), "tree 2\njobs.0 string :B\njobs.1 string :d");
+
+        assert!(!store.execute("pop jobs".parse().unwrap()).is_err());
+        assert!(!store.execute("pop jobs".parse().unwrap()).is_err());
+        assert!(store.execute("pop jobs".parse().unwrap()).is_err());
+        assert!(store.execute("push . string".parse().unwrap()).is_err());
+
+        let events: Vec<String> = store.take_events().iter().map(|e| e.to_string()).collect();
+        assert_eq!(events[1..5].to_vec(), vec![
+            "create jobs.0 string :b", "create jobs.1 string :d", "create jobs.0 string :a", "create jobs.2 string :c",
+        ]);
+        assert_eq!(events.last().unwrap(), "delete jobs.0");
+    }
+
+    #[test]
+    fn list_items_rollback_and_snapshot() {
+        let mut store = Store::new();
+        assert!(!store.execute("create . jobs list".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs integer 1".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs list".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs.1 boolean true".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs integer".parse().unwrap()).is_err());
+
+        // Items move around, so they can't expire
+        assert!(store.execute("ttl jobs.0 10".parse().unwrap()).is_err());
+        assert!(store.execute("update -t 10 jobs.0 2".parse().unwrap()).is_err());
+        assert!(!store.execute("push jobs map".parse().unwrap()).is_err());
+        assert!(store.execute("create -e jobs.3 foo string".parse().unwrap()).is_err());
+        assert!(!store.execute("pop jobs".parse().unwrap()).is_err());
+
+        store.begin();
+        assert!(!store.execute("insert jobs 0 string :x".parse().unwrap()).is_err());
+        assert!(!store.execute("update jobs.1 5".parse().unwrap()).is_err());
+        assert!(store.execute("delete jobs.2".parse().unwrap()).is_err());
+        assert!(!store.execute("delete -r jobs.2".parse().unwrap()).is_err());
+        assert!(!store.execute("pop jobs -1".parse().unwrap()).is_err());
+        store.rollback();
+
+        let dump = store.execute("dump jobs".parse().unwrap()).to_string();
+        assert_eq!(dump, "tree 5\njobs list 3\njobs.0 integer 1\njobs.1 list 1\njobs.1.0 boolean true\njobs.2 integer 0");
+
+        let mut copy = Store::new();
+        for cmd in store.snapshot() {
+            assert!(!copy.execute(cmd).is_err());
+        }
+        assert_eq!(copy.execute("dump jobs".parse().unwrap()).to_string(), dump);
+    }
 }
@@ -0,0 +1,190 @@
+use futures::{Async, Future, Poll};
+use openssl::ssl::{self, HandshakeError, MidHandshakeSslStream, ShutdownResult, SslAcceptor, SslFiletype};
+use openssl::ssl::{SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::X509Name;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Sets up TLS with the certificate chain and private key in the given PEM files. If
+/// `client_ca` is given, clients have to present a certificate signed by one of the
+/// certificates in it.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(tls_error)?;
+    builder.set_certificate_chain_file(cert).map_err(tls_error)?;
+    builder.set_private_key_file(key, SslFiletype::PEM).map_err(tls_error)?;
+    builder.check_private_key().map_err(tls_error)?;
+
+    if let Some(ca) = client_ca {
+        builder.set_ca_file(ca).map_err(tls_error)?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(ca).map_err(tls_error)?);
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(builder.build())
+}
+
+/// Performs the server side of the TLS handshake on `socket`.
+pub fn accept<S: Read + Write>(acceptor: &SslAcceptor, socket: S) -> Accept<S> {
+    Accept(Handshake::Start(acceptor.clone(), socket))
+}
+
+/// A future that resolves to the encrypted stream once the handshake is done.
+pub struct Accept<S>(Handshake<S>);
+
+enum Handshake<S> {
+    Start(SslAcceptor, S),
+    Waiting(MidHandshakeSslStream<S>),
+    Done,
+}
+
+/// A stream that is encrypted with TLS, which can be used like the socket it wraps.
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S: Read + Write> Future for Accept<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let res = match ::std::mem::replace(&mut self.0, Handshake::Done) {
+            Handshake::Start(acceptor, socket) => acceptor.accept(socket),
+            Handshake::Waiting(mid)            => mid.handshake(),
+            Handshake::Done                    => panic!("handshake polled after it was done"),
+        };
+
+        match res {
+            Ok(stream)                           => Ok(Async::Ready(TlsStream(stream))),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                // The socket has registered the task to be woken up when it's ready
+                self.0 = Handshake::Waiting(mid);
+                Ok(Async::NotReady)
+            },
+            Err(HandshakeError::Failure(mid))    => Err(io::Error::new(io::ErrorKind::Other, mid.error().to_string())),
+            Err(HandshakeError::SetupFailure(e)) => Err(tls_error(e)),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(ShutdownResult::Sent) | Ok(ShutdownResult::Received) => (),
+            Err(ref e) if e.code() == ssl::ErrorCode::ZERO_RETURN  => (),
+            Err(e) => {
+                return match e.into_io_error() {
+                    Ok(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+                    Ok(e)  => Err(e),
+                    Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+                };
+            },
+        }
+
+        self.0.get_mut().shutdown()
+    }
+}
+
+fn tls_error<E>(e: E) -> io::Error where
+    E: Into<Box<::std::error::Error + Send + Sync>>
+{
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::SslConnector;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::env;
+    use std::fs::{self, File};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::thread;
+
+    /// Writes a self-signed certificate for localhost and its key to temporary files.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", "localhost").unwrap();
+        let subject = subject.build();
+
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        let dir       = env::temp_dir();
+        let cert_path = dir.join(format!("um-test-{}-{}.crt", name, ::std::process::id()));
+        let key_path  = dir.join(format!("um-test-{}-{}.key", name, ::std::process::id()));
+        File::create(&cert_path).unwrap().write_all(&cert.build().to_pem().unwrap()).unwrap();
+        File::create(&key_path).unwrap().write_all(&key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Connects a client to a server using `acceptor`, and returns whether the handshake
+    /// succeeded on both sides.
+    fn handshake(acceptor: SslAcceptor, ca: &Path, client_cert: Option<(&Path, &Path)>) -> bool {
+        let (a, b) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || acceptor.accept(a).map(|mut s| s.write_all(b"ok").is_ok()).unwrap_or(false));
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_ca_file(ca).unwrap();
+        if let Some((cert, key)) = client_cert {
+            connector.set_certificate_file(cert, SslFiletype::PEM).unwrap();
+            connector.set_private_key_file(key, SslFiletype::PEM).unwrap();
+        }
+        let client = connector.build().connect("localhost", b).ok().map_or(false, |mut s| {
+            let mut buf = [0; 2];
+            s.read_exact(&mut buf).is_ok() && &buf == b"ok"
+        });
+
+        server.join().unwrap() && client
+    }
+
+    #[test]
+    fn handshakes() {
+        let (cert, key)               = self_signed("server");
+        let (client_cert, client_key) = self_signed("client");
+
+        assert!(acceptor(&key, &cert, None).is_err());
+        assert!(handshake(acceptor(&cert, &key, None).unwrap(), &cert, None));
+
+        // Clients need a certificate signed by the CA if one is configured
+        let verifying = || acceptor(&cert, &key, Some(&client_cert)).unwrap();
+        assert!(!handshake(verifying(), &cert, None));
+        assert!(!handshake(verifying(), &cert, Some((&cert, &key))));
+        assert!(handshake(verifying(), &cert, Some((&client_cert, &client_key))));
+
+        for path in &[ cert, key, client_cert, client_key ] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
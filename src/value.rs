@@ -1,11 +1,15 @@
 use node::Node;
-use std::collections::HashMap;
+use nodespec;
+use std::borrow::Cow;
+use std::collections::{hash_map, HashMap};
 use std::fmt;
+use std::iter::Enumerate;
+use std::slice;
 use std::str::FromStr;
 
 pub type Map = HashMap<String, Node>;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Value {
     Empty,
     Boolean(bool),
@@ -13,9 +17,10 @@ pub enum Value {
     Float(f64),
     String(String),
     Map(Map),
+    List(Vec<Node>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ValType {
     Empty,
     Boolean,
@@ -23,6 +28,7 @@ pub enum ValType {
     Float,
     String,
     Map,
+    List,
 }
 
 impl Value {
@@ -34,6 +40,7 @@ impl Value {
             Value::Float(_)   => ValType::Float,
             Value::String(_)  => ValType::String,
             Value::Map(_)     => ValType::Map,
+            Value::List(_)    => ValType::List,
         }
     }
 
@@ -45,8 +52,85 @@ impl Value {
             ValType::Float   => Value::Float(s.parse().map_err(|_| "invalid float")?),
             ValType::String  => Value::String(s.to_string()),
             ValType::Map     => return Err("can't update a map node"),
+            ValType::List    => return Err("can't update a list node"),
         })
     }
+
+    /// Returns the sum (or difference, if `subtract` is set) of an integer or float value and
+    /// `amount`, which is parsed as the same type.
+    pub fn add(&self, amount: &str, subtract: bool) -> Result<Value, &'static str> {
+        match *self {
+            Value::Integer(i) => {
+                let amount: i64 = amount.parse().map_err(|_| "invalid integer")?;
+                let sum = if subtract { i.checked_sub(amount) } else { i.checked_add(amount) };
+                sum.map(Value::Integer).ok_or("integer overflow")
+            },
+            Value::Float(f) => {
+                let amount: f64 = amount.parse().map_err(|_| "invalid float")?;
+                Ok(Value::Float(if subtract { f - amount } else { f + amount }))
+            },
+            _ => Err("value is not an integer or float"),
+        }
+    }
+
+    /// Returns a string value with `s` added to the end, or to the start if `prepend` is set.
+    pub fn concat(&self, s: &str, prepend: bool) -> Result<Value, &'static str> {
+        match self {
+            Value::String(old) if prepend => Ok(Value::String(format!("{}{}", s, old))),
+            Value::String(old)            => Ok(Value::String(format!("{}{}", old, s))),
+            _                             => Err("value is not a string"),
+        }
+    }
+
+    pub fn toggle(&self) -> Result<Value, &'static str> {
+        match *self {
+            Value::Boolean(b) => Ok(Value::Boolean(!b)),
+            _                 => Err("value is not a boolean"),
+        }
+    }
+
+    /// Returns the string that `Value::from_str` parses back into this value, or `None` for
+    /// values that can't be set this way.
+    pub fn payload(&self) -> Option<String> {
+        match self {
+            Value::Empty      => None,
+            Value::Boolean(b) => Some(b.to_string()),
+            Value::Integer(i) => Some(i.to_string()),
+            Value::Float(f)   => Some(f.to_string()),
+            Value::String(s)  => Some(s.clone()),
+            Value::Map(_)     => None,
+            Value::List(_)    => None,
+        }
+    }
+
+    /// Returns the children of a map or list along with their names. The items of a list are
+    /// named after their index.
+    pub fn children(&self) -> Children {
+        match self {
+            Value::Map(m)  => Children::Map(m.iter()),
+            Value::List(l) => Children::List(l.iter().enumerate()),
+            _              => Children::None,
+        }
+    }
+}
+
+/// An iterator over the children of a value, see `Value::children`.
+pub enum Children<'a> {
+    Map(hash_map::Iter<'a, String, Node>),
+    List(Enumerate<slice::Iter<'a, Node>>),
+    None,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = (Cow<'a, str>, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Children::Map(iter)  => iter.next().map(|(name, child)| (Cow::Borrowed(name.as_str()), child)),
+            Children::List(iter) => iter.next().map(|(i, child)| (Cow::Owned(i.to_string()), child)),
+            Children::None       => None,
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -56,8 +140,23 @@ impl fmt::Display for Value {
             Value::Boolean(b) => write!(fmt, "boolean {}", b),
             Value::Integer(i) => write!(fmt, "integer {}", i),
             Value::Float(f)   => write!(fmt, "float {}",   f),
-            Value::String(s)  => write!(fmt, "string :{}", s),
+            Value::String(s)  => write!(fmt, "string :{}", nodespec::escape_value(s)),
             Value::Map(m)     => write!(fmt, "map {}",     m.len()),
+            Value::List(l)    => write!(fmt, "list {}",    l.len()),
+        }
+    }
+}
+
+impl fmt::Display for ValType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValType::Empty   => write!(fmt, "empty"),
+            ValType::Boolean => write!(fmt, "boolean"),
+            ValType::Integer => write!(fmt, "integer"),
+            ValType::Float   => write!(fmt, "float"),
+            ValType::String  => write!(fmt, "string"),
+            ValType::Map     => write!(fmt, "map"),
+            ValType::List    => write!(fmt, "list"),
         }
     }
 }
@@ -73,6 +172,7 @@ impl FromStr for ValType {
             "float"   => Ok(ValType::Float),
             "string"  => Ok(ValType::String),
             "map"     => Ok(ValType::Map),
+            "list"    => Ok(ValType::List),
             _         => Err("invalid type"),
         }
     }
@@ -85,4 +185,51 @@ mod tests {
     #[test]
     fn parse_simple_nodespec() {
     }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(Value::Integer(41).add("1", false).map(|v| v.to_string()), Ok("integer 42".to_string()));
+        assert_eq!(Value::Integer(0).add("5", true).map(|v| v.to_string()), Ok("integer -5".to_string()));
+        assert_eq!(Value::Float(1.5).add("0.25", true).map(|v| v.to_string()), Ok("float 1.25".to_string()));
+
+        assert!(Value::Integer(i64::max_value()).add("1", false).is_err());
+        assert!(Value::Integer(i64::min_value()).add("1", true).is_err());
+        assert!(Value::Integer(1).add("1.5", false).is_err());
+        assert!(Value::String("1".to_string()).add("1", false).is_err());
+    }
+
+    #[test]
+    fn string_and_boolean_mutations() {
+        let s = Value::String("bar".to_string());
+        assert_eq!(s.concat("baz", false).map(|v| v.to_string()), Ok("string :barbaz".to_string()));
+        assert_eq!(s.concat("foo", true).map(|v| v.to_string()), Ok("string :foobar".to_string()));
+        assert!(Value::Integer(1).concat("foo", false).is_err());
+
+        assert_eq!(Value::Boolean(false).toggle().map(|v| v.to_string()), Ok("boolean true".to_string()));
+        assert!(Value::Integer(0).toggle().is_err());
+    }
+
+    #[test]
+    fn payload_roundtrip() {
+        let values = vec![
+            Value::Boolean(true),
+            Value::Integer(-42),
+            Value::Float(-0.0),
+            Value::Float(1.0 / 3.0),
+            Value::Float(::std::f64::INFINITY),
+            Value::String("hello world".to_string()),
+        ];
+
+        for val in values {
+            let payload = val.payload().unwrap();
+            let parsed  = Value::from_str(&payload, &val.valtype()).unwrap();
+            assert_eq!(parsed.to_string(), val.to_string());
+        }
+
+        let nan = Value::from_str(&Value::Float(::std::f64::NAN).payload().unwrap(), &ValType::Float);
+        match nan {
+            Ok(Value::Float(f)) => assert!(f.is_nan()),
+            _                   => panic!("expected NaN but got {:?}", nan),
+        }
+    }
 }
@@ -0,0 +1,514 @@
+use command::Command;
+use nodespec::NodeSpec;
+use response::Response;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use store::Store;
+
+/// The log is compacted when it grows past this size, or past twice its size after the last
+/// compaction, whichever is larger.
+const COMPACT_THRESHOLD: u64 = 1024 * 1024;
+
+/// An append-only log of the commands that changed the store.
+///
+/// Every record is a single line containing the CRC-32 of the command (as 8 hex digits), a
+/// space, and the command itself:
+///
+///     3899bd29 create . foo string
+///
+/// Commands are escaped to fit on their line: backslashes are doubled, and newlines in values
+/// and names are written as `\n` and `\r`. The checksum covers the escaped command.
+///
+/// This lets a record that was only partially written before a crash be told apart from a
+/// complete one. The commands of a transaction are wrapped in `begin` and `commit` records, and
+/// are only replayed if the `commit` record made it to the log.
+///
+/// Compacting the log also writes `version <nodespec> <version>` records, which restore the
+/// versions of the nodes, since replaying the compacted commands doesn't.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    compact_at: u64,
+    /// Whether a change couldn't be written, so the log has to be rewritten from the store
+    /// before anything is appended to it again
+    missing_changes: bool,
+}
+
+enum Record {
+    Command(Command),
+    Version(NodeSpec, u64),
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if it doesn't exist yet, and replays it into `store`.
+    ///
+    /// If the last record is incomplete or corrupt, it is assumed to be torn by a crash while
+    /// writing it, and is dropped from the log. A bad record anywhere else is an error.
+    pub fn open(path: &Path, store: &mut Store) -> io::Result<Wal> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let size = Self::replay(&data, store)?;
+        if size < data.len() as u64 {
+            println!("dropping torn record(s) at the end of {}", path.display());
+            file.set_len(size)?;
+        }
+
+        // Changes made while replaying have already happened
+        store.take_events();
+
+        Ok(Wal {
+            path: path.to_path_buf(),
+            file,
+            size,
+            compact_at: cmp::max(COMPACT_THRESHOLD, size * 2),
+            missing_changes: false,
+        })
+    }
+
+    /// Replays the records in `data` and returns the length of the valid part.
+    fn replay(data: &[u8], store: &mut Store) -> io::Result<u64> {
+        let mut offset = 0;
+
+        // The commands of the current transaction, and the offset of its `begin` record
+        let mut transaction: Option<(Vec<Command>, usize)> = None;
+
+        while offset < data.len() {
+            let end = match data[offset..].iter().position(|&b| b == b'\n') {
+                Some(pos) => offset + pos,
+                None      => break, // torn record
+            };
+
+            let record = match Self::parse_record(&data[offset..end]) {
+                Ok(record)                      => record,
+                Err(_) if end + 1 == data.len() => break, // torn record
+                Err(e)                          => return Err(bad_record(offset, e)),
+            };
+
+            let cmd = match record {
+                Record::Command(cmd) => cmd,
+                Record::Version(nodespec, version) => {
+                    if transaction.is_some() {
+                        return Err(bad_record(offset, "unexpected version record"));
+                    }
+                    store.get_node(&nodespec).map_err(|e| bad_record(offset, e))?.set_version(version);
+                    offset = end + 1;
+                    continue;
+                },
+            };
+
+            match (cmd, transaction.take()) {
+                (Command::Begin, None) => {
+                    transaction = Some((Vec::new(), offset));
+                },
+                (Command::Commit, Some((cmds, begin))) => {
+                    for cmd in cmds {
+                        Self::replay_command(cmd, begin, store)?;
+                    }
+                },
+                (Command::Begin, Some(_)) | (Command::Commit, None) => {
+                    return Err(bad_record(offset, "unexpected transaction record"));
+                },
+                (cmd, Some((mut cmds, begin))) => {
+                    cmds.push(cmd);
+                    transaction = Some((cmds, begin));
+                },
+                (cmd, None) => {
+                    Self::replay_command(cmd, offset, store)?;
+                },
+            }
+
+            offset = end + 1;
+        }
+
+        // A transaction that wasn't committed is torn as a whole
+        match transaction {
+            Some((_, begin)) => Ok(begin as u64),
+            None             => Ok(offset as u64),
+        }
+    }
+
+    fn replay_command(cmd: Command, offset: usize, store: &mut Store) -> io::Result<()> {
+        if let Response::Error(e) = store.execute(cmd) {
+            let msg = format!("record at offset {} failed to replay: {}", offset, e);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(())
+    }
+
+    fn parse_record(record: &[u8]) -> Result<Record, &'static str> {
+        let record = ::std::str::from_utf8(record).map_err(|_| "invalid utf-8")?;
+
+        if record.len() < 9 || &record[8..9] != " " {
+            return Err("malformed record");
+        }
+
+        let crc = u32::from_str_radix(&record[..8], 16).map_err(|_| "malformed checksum")?;
+        let cmd = &record[9..];
+
+        if crc32(cmd.as_bytes()) != crc {
+            return Err("checksum mismatch");
+        }
+
+        let cmd = unescape(cmd)?;
+
+        if cmd.starts_with("version ") {
+            let args     = &cmd[8..];
+            let pos      = args.rfind(' ').ok_or("malformed version record")?;
+            let nodespec = args[..pos].parse()?;
+            let version  = args[pos + 1..].parse().map_err(|_| "malformed version record")?;
+            return Ok(Record::Version(nodespec, version));
+        }
+
+        cmd.parse().map(Record::Command)
+    }
+
+    fn format_record(cmd: &str) -> String {
+        let cmd = escape(cmd);
+        format!("{:08x} {}\n", crc32(cmd.as_bytes()), cmd)
+    }
+
+    /// Appends a command to the log and waits until it has been written to disk.
+    pub fn append(&mut self, cmd: &str) -> io::Result<()> {
+        let record = Self::format_record(cmd);
+        self.write(&record)
+    }
+
+    /// Appends the commands of a transaction to the log, so that they are replayed either all
+    /// together or not at all, and waits until they have been written to disk.
+    pub fn append_transaction(&mut self, cmds: &[String]) -> io::Result<()> {
+        let mut records = Self::format_record(&Command::Begin.to_string());
+        for cmd in cmds {
+            records.push_str(&Self::format_record(cmd));
+        }
+        records.push_str(&Self::format_record(&Command::Commit.to_string()));
+        self.write(&records)
+    }
+
+    /// If the records can't be written, whatever part of them was is dropped again, and
+    /// nothing more is written until the log has been rewritten by `compact`, since records
+    /// after the missing ones might not replay.
+    fn write(&mut self, records: &str) -> io::Result<()> {
+        if self.missing_changes {
+            return Err(io::Error::new(io::ErrorKind::Other, "log has to be compacted first"));
+        }
+
+        let res = self.file.write_all(records.as_bytes()).and_then(|_| self.file.sync_data());
+        if res.is_err() {
+            let _ = self.file.set_len(self.size);
+            self.missing_changes = true;
+        }
+        res?;
+
+        self.size += records.len() as u64;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.missing_changes || self.size >= self.compact_at
+    }
+
+    /// Replaces the log with the shortest list of commands that rebuilds `store`.
+    ///
+    /// The new log is written next to the current one, and then moved over it, so a crash
+    /// during compaction leaves either the old or the new log intact.
+    pub fn compact(&mut self, store: &Store) -> io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut size = 0;
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            let cmds     = store.snapshot().into_iter().map(|cmd| cmd.to_string());
+            let versions = store.versions().into_iter()
+                               .map(|(nodespec, version)| format!("version {} {}", nodespec, version));
+
+            for cmd in cmds.chain(versions) {
+                let record = Self::format_record(&cmd);
+                tmp.write_all(record.as_bytes())?;
+                size += record.len() as u64;
+            }
+            tmp.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, &self.path)?;
+
+        // The rename only survives a crash once the directory has been written to disk as well
+        let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+
+        self.file            = OpenOptions::new().append(true).open(&self.path)?;
+        self.size            = size;
+        self.compact_at      = cmp::max(COMPACT_THRESHOLD, size * 2);
+        self.missing_changes = false;
+        Ok(())
+    }
+}
+
+fn bad_record(offset: usize, e: &str) -> io::Error {
+    let msg = format!("bad record at offset {}: {}", offset, e);
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Escapes a command, so its record ends at the first newline.
+fn escape(cmd: &str) -> String {
+    let mut escaped = String::with_capacity(cmd.len());
+    for c in cmd.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _    => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Resolves the escapes added by `escape`.
+fn unescape(s: &str) -> Result<String, &'static str> {
+    let mut cmd   = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            cmd.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => cmd.push('\\'),
+            Some('n')  => cmd.push('\n'),
+            Some('r')  => cmd.push('\r'),
+            _          => return Err("malformed escape"),
+        }
+    }
+    Ok(cmd)
+}
+
+/// Computes the CRC-32 (as used by zlib and PNG) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use value::{ValType, Value};
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("um-test-{}-{}.log", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn execute_logged(store: &mut Store, wal: &mut Wal, cmd: &str) {
+        assert!(!store.execute(cmd.parse().unwrap()).is_err());
+        wal.append(cmd).unwrap();
+    }
+
+    fn read_string(store: &mut Store, nodespec: &str) -> String {
+        match store.execute(Command::Read(nodespec.parse().unwrap())) {
+            Response::Value(&Value::String(ref s)) => s.clone(),
+            res                                    => panic!("expected a string value but got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn replay_log() {
+        let path = log_path("replay");
+
+        {
+            let mut store = Store::new();
+            let mut wal   = Wal::open(&path, &mut store).unwrap();
+            assert!(wal.is_empty());
+            execute_logged(&mut store, &mut wal, "create . foo string");
+            execute_logged(&mut store, &mut wal, "update foo :hello world");
+        }
+
+        let mut store = Store::new();
+        assert!(!Wal::open(&path, &mut store).unwrap().is_empty());
+        assert_eq!(read_string(&mut store, "foo"), "hello world");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_newlines() {
+        let path  = log_path("newlines");
+        let value = "first line\r\nsecond \\n line in C:\\new\\".to_string();
+
+        {
+            let mut store = Store::new();
+            let mut wal   = Wal::open(&path, &mut store).unwrap();
+            let create = Command::Create(NodeSpec::root(), "a\nb".to_string(), ValType::String, None, false);
+            execute_logged(&mut store, &mut wal, &create.to_string());
+            let update = Command::Update(r"a\nb".parse().unwrap(), value.clone(), None);
+            execute_logged(&mut store, &mut wal, &update.to_string());
+        }
+
+        let mut store = Store::new();
+        Wal::open(&path, &mut store).unwrap();
+        assert_eq!(read_string(&mut store, r"a\nb"), value);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drop_torn_record() {
+        let path = log_path("torn");
+
+        {
+            let mut store = Store::new();
+            let mut wal   = Wal::open(&path, &mut store).unwrap();
+            execute_logged(&mut store, &mut wal, "create . foo string");
+            execute_logged(&mut store, &mut wal, "update foo :first");
+        }
+
+        // Simulate a crash halfway through writing a record
+        let complete = fs::metadata(&path).unwrap().len();
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            let record   = Wal::format_record("update foo :second");
+            file.write_all(&record.as_bytes()[..record.len() / 2]).unwrap();
+        }
+
+        let mut store = Store::new();
+        Wal::open(&path, &mut store).unwrap();
+        assert_eq!(read_string(&mut store, "foo"), "first");
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+
+        // A complete record with a bad checksum at the end is dropped too
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(b"00000000 update foo :second\n").unwrap();
+        }
+
+        let mut store = Store::new();
+        Wal::open(&path, &mut store).unwrap();
+        assert_eq!(read_string(&mut store, "foo"), "first");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drop_torn_transaction() {
+        let path = log_path("transaction");
+
+        {
+            let mut store = Store::new();
+            let mut wal   = Wal::open(&path, &mut store).unwrap();
+            execute_logged(&mut store, &mut wal, "create . foo string");
+            wal.append_transaction(&[ "update foo :first".to_string(), "create . bar string".to_string() ]).unwrap();
+        }
+
+        // Simulate a crash after writing only part of a transaction
+        let complete = fs::metadata(&path).unwrap().len();
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(Wal::format_record("begin").as_bytes()).unwrap();
+            file.write_all(Wal::format_record("update foo :second").as_bytes()).unwrap();
+        }
+
+        let mut store = Store::new();
+        Wal::open(&path, &mut store).unwrap();
+        assert_eq!(read_string(&mut store, "foo"), "first");
+        assert_eq!(read_string(&mut store, "bar"), "");
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_corrupt_record() {
+        let path = log_path("corrupt");
+
+        {
+            let mut file = File::create(&path).unwrap();
+            file.write_all(b"00000000 create . foo string\n").unwrap();
+            file.write_all(Wal::format_record("create . bar string").as_bytes()).unwrap();
+        }
+
+        assert!(Wal::open(&path, &mut Store::new()).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_log() {
+        let path = log_path("compact");
+
+        {
+            let mut store = Store::new();
+            let mut wal   = Wal::open(&path, &mut store).unwrap();
+            execute_logged(&mut store, &mut wal, "create . foo string");
+            for i in 0..100 {
+                execute_logged(&mut store, &mut wal, &format!("update foo :{}", i));
+            }
+
+            let before = fs::metadata(&path).unwrap().len();
+            wal.compact(&store).unwrap();
+            assert!(fs::metadata(&path).unwrap().len() < before);
+
+            execute_logged(&mut store, &mut wal, "create . bar string");
+        }
+
+        let mut store = Store::new();
+        Wal::open(&path, &mut store).unwrap();
+        assert_eq!(read_string(&mut store, "foo"), "99");
+        assert_eq!(read_string(&mut store, "bar"), "");
+
+        // Versions survive compaction
+        assert_eq!(store.get_node(&"foo".parse().unwrap()).unwrap().version(), 100);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rewrite_missing_changes() {
+        let path = log_path("missing");
+
+        {
+            let mut store = Store::new();
+            let mut wal   = Wal::open(&path, &mut store).unwrap();
+            execute_logged(&mut store, &mut wal, "create . foo string");
+
+            // After a failed write, nothing is appended until the store has been written out
+            wal.missing_changes = true;
+            assert!(!store.execute("update foo :lost".parse().unwrap()).is_err());
+            assert!(wal.append("update foo :lost").is_err());
+            assert!(wal.needs_compaction());
+
+            wal.compact(&store).unwrap();
+            assert!(!wal.needs_compaction());
+            execute_logged(&mut store, &mut wal, "create . bar string");
+        }
+
+        let mut store = Store::new();
+        Wal::open(&path, &mut store).unwrap();
+        assert_eq!(read_string(&mut store, "foo"), "lost");
+        assert_eq!(read_string(&mut store, "bar"), "");
+
+        fs::remove_file(&path).unwrap();
+    }
+}